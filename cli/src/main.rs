@@ -0,0 +1,323 @@
+//! Inspect and extract images without mounting them.
+//!
+//! Unlike the FUSE binary this only needs `std`, so it works on any platform.
+
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Component, Path, PathBuf},
+};
+
+/// Limits recursion when printing trees.
+const MAX_DEPTH: usize = 1024;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+	let mut a = std::env::args().skip(1);
+	let cmd = a
+		.next()
+		.ok_or("expected command (ls, cat, extract, info, complete, tree or fsck)")?;
+	let mut f = File::open(a.next().ok_or("expected image path")?)?;
+	let header = nrofs::Header::from_reader(&mut f)?;
+	let mut buf = [0; 255];
+
+	match &*cmd {
+		"ls" => {
+			for i in 0..header.file_count() {
+				let e = header.get(i, nrofs::reader_io(&mut f)).unwrap()?;
+				let name = e.name_from_reader(&mut buf, &mut f)?;
+				println!("{:>8}  {}", e.size(), String::from_utf8_lossy(name));
+			}
+		}
+		"cat" => {
+			let path = a.next().ok_or("expected file path")?;
+			let path = path.trim_start_matches('/').as_bytes();
+			let i = header.resolve_path(path, nrofs::reader_io(&mut f))?;
+			let i = i.ok_or("file not found")?;
+			let e = header.get(i, nrofs::reader_io(&mut f)).unwrap()?;
+			f.seek(SeekFrom::Start(e.offset(&header)))?;
+			io::copy(
+				&mut (&mut f).take(e.size().into()),
+				&mut io::stdout().lock(),
+			)?;
+		}
+		"extract" => {
+			let dest = PathBuf::from(a.next().ok_or("expected destination directory")?);
+			let sparse = match a.next().as_deref() {
+				None => false,
+				Some("--sparse") => true,
+				Some(arg) => Err(format!("unexpected argument {:?}", arg))?,
+			};
+			// Block sized chunks keep holes aligned to the blocks of the destination.
+			let mut chunk = vec![0; header.block_size().as_bytes().min(1 << 16) as usize];
+			for i in 0..header.file_count() {
+				let e = header.get(i, nrofs::reader_io(&mut f)).unwrap()?;
+				let name = e.name_from_reader(&mut buf, &mut f)?;
+				let path = extract_path(&dest, name).ok_or_else(|| {
+					format!("refusing to extract {:?}", String::from_utf8_lossy(name))
+				})?;
+				if let Some(p) = path.parent() {
+					fs::create_dir_all(p)?;
+				}
+				let mut out = File::create(&path)?;
+				f.seek(SeekFrom::Start(e.offset(&header)))?;
+				let mut left = u64::from(e.size());
+				while left > 0 {
+					let n = left.min(chunk.len() as u64) as usize;
+					let b = &mut chunk[..n];
+					f.read_exact(b)?;
+					if sparse && b.iter().all(|&c| c == 0) {
+						out.seek(SeekFrom::Current(b.len() as i64))?;
+					} else {
+						out.write_all(b)?;
+					}
+					left -= b.len() as u64;
+				}
+				// Seeking alone doesn't extend the file over a trailing hole.
+				out.set_len(e.size().into())?;
+			}
+		}
+		"info" => {
+			println!("block size: {}", header.block_size().as_bytes());
+			println!("file count: {}", header.file_count());
+			let end = header.data_region_end(nrofs::reader_io(&mut f))?;
+			let len = f.metadata()?.len();
+			println!("data end: {}", end);
+			match len.checked_sub(end) {
+				Some(n) => println!("trailing bytes: {}", n),
+				None => println!("missing bytes: {}", end - len),
+			}
+			let mut v = [0; 255];
+			let mut m = header.metadata(nrofs::reader_io(&mut f))?;
+			while let Some(kv) = m.next(&mut buf, &mut v) {
+				let (k, v) = kv?;
+				let mut out = io::stdout().lock();
+				out.write_all(k)?;
+				out.write_all(b": ")?;
+				out.write_all(v)?;
+				out.write_all(b"\n")?;
+			}
+		}
+		"complete" => {
+			let prefix = a.next().unwrap_or_default();
+			let prefix = prefix.trim_start_matches('/').as_bytes();
+			let mut out = io::stdout().lock();
+			for name in header.complete_prefix(prefix, 1000, nrofs::reader_io(&mut f))? {
+				out.write_all(&name)?;
+				out.write_all(b"\n")?;
+			}
+		}
+		"tree" => {
+			let as_json = match a.next().as_deref() {
+				None => false,
+				Some("--json") => true,
+				Some(arg) => Err(format!("unexpected argument {:?}", arg))?,
+			};
+			let tree = nrofs::Tree::new(&header, MAX_DEPTH, nrofs::reader_io(&mut f))?;
+			let mut out = io::stdout().lock();
+			if as_json {
+				json(&tree, 0, &mut out)?;
+				out.write_all(b"\n")?;
+			} else {
+				print_tree(&tree, 0, 0, &mut out)?;
+			}
+		}
+		"fsck" => {
+			let repair = match a.next().as_deref() {
+				None => None,
+				Some("--repair") => Some(a.next().ok_or("expected output path")?),
+				Some(arg) => Err(format!("unexpected argument {:?}", arg))?,
+			};
+			// Everything that can still be read is added, which also sorts the entries.
+			let mut w = nrofs::Writer::new(header.block_size());
+			w.remove_metadata(b"tool");
+			w.remove_metadata(b"version");
+			w.set_path_index(header.has_path_index());
+			let mut problems = 0;
+			let mut problem = |s: String| {
+				println!("{}", s);
+				problems += 1;
+			};
+			let mut v = [0; 255];
+			match header.metadata(nrofs::reader_io(&mut f)) {
+				Ok(mut m) => {
+					while let Some(kv) = m.next(&mut buf, &mut v) {
+						match kv {
+							Ok((k, v)) => w.set_metadata(k, v)?,
+							Err(e) => {
+								problem(format!("metadata: {}", e));
+								break;
+							}
+						}
+					}
+				}
+				Err(e) => problem(format!("metadata: {}", e)),
+			}
+			let mut prev = None::<Vec<u8>>;
+			for i in 0..header.file_count() {
+				let e = match header.get_checked(i, nrofs::reader_io(&mut f)).unwrap() {
+					Ok(e) => e,
+					Err(nrofs::EntryError::Corrupt) => {
+						problem(format!("entry {}: data points outside of the image", i));
+						continue;
+					}
+					Err(e) => Err(e)?,
+				};
+				let name = match e.name_checked(&mut buf, nrofs::reader_io(&mut f)) {
+					Ok(n) => n,
+					Err(nrofs::EntryError::Corrupt) => {
+						problem(format!("entry {}: name points outside of the image", i));
+						continue;
+					}
+					Err(e) => Err(e)?,
+				};
+				let lossy = String::from_utf8_lossy(name);
+				match prev.as_deref().map(|p| p.cmp(name)) {
+					Some(std::cmp::Ordering::Equal) => {
+						problem(format!("entry {}: duplicate name {:?}", i, lossy))
+					}
+					Some(std::cmp::Ordering::Greater) => {
+						problem(format!("entry {}: {:?} is out of order", i, lossy))
+					}
+					_ => {}
+				}
+				prev = Some(name.into());
+				if repair.is_some() {
+					let mut data = vec![0; e.size() as usize];
+					f.seek(SeekFrom::Start(e.offset(&header)))?;
+					f.read_exact(&mut data)?;
+					w.add(name, &data)?;
+				}
+			}
+			if let Some(out) = repair {
+				// Never overwrite anything, in particular not the damaged image.
+				let mut out = OpenOptions::new().write(true).create_new(true).open(out)?;
+				out.write_all(&w.finish()?)?;
+			}
+			match problems {
+				0 => println!("no problems found"),
+				1 => Err("1 problem found")?,
+				n => Err(format!("{} problems found", n))?,
+			}
+		}
+		_ => Err(format!("unknown command {:?}", cmd))?,
+	}
+	Ok(())
+}
+
+/// The path in `dest` to extract the file `name` to.
+///
+/// Returns `None` if the name has no components or any of them is not a plain name on this
+/// platform, such as `..`, a drive prefix or a component containing a separator.
+fn extract_path(dest: &Path, name: &[u8]) -> Option<PathBuf> {
+	let mut path = dest.to_path_buf();
+	let mut any = false;
+	for c in name.split(|&c| c == b'/').filter(|c| !c.is_empty()) {
+		let c = std::str::from_utf8(c).ok()?;
+		match Path::new(c).components().collect::<Vec<_>>()[..] {
+			[Component::Normal(n)] if n == c => path.push(n),
+			_ => return None,
+		}
+		any = true;
+	}
+	any.then_some(path)
+}
+
+/// Print the children of `dir` indented by `depth`, with the size of files.
+fn print_tree(tree: &nrofs::Tree, dir: u32, depth: usize, out: &mut impl Write) -> io::Result<()> {
+	for (name, node) in tree.children(dir) {
+		let name = String::from_utf8_lossy(name);
+		match node {
+			nrofs::Node::File(i) => {
+				let size = tree.entry(i).unwrap().size();
+				writeln!(out, "{:depth$}{}  {}", "", name, size, depth = depth * 2)?
+			}
+			nrofs::Node::Dir(d) => {
+				writeln!(out, "{:depth$}{}/", "", name, depth = depth * 2)?;
+				print_tree(tree, d, depth + 1, out)?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Write `dir` as a JSON object mapping names to the size of files or to subdirectories.
+///
+/// Keys are sorted by the bytes of the name so the output is stable.
+/// Names that aren't valid UTF-8 are converted lossily.
+fn json(tree: &nrofs::Tree, dir: u32, out: &mut impl Write) -> io::Result<()> {
+	out.write_all(b"{")?;
+	for (i, (name, node)) in tree.children(dir).enumerate() {
+		if i > 0 {
+			out.write_all(b",")?;
+		}
+		out.write_all(b"\"")?;
+		for c in String::from_utf8_lossy(name).chars() {
+			match c {
+				'"' => out.write_all(b"\\\"")?,
+				'\\' => out.write_all(b"\\\\")?,
+				c if c < ' ' => write!(out, "\\u{:04x}", c as u32)?,
+				c => write!(out, "{}", c)?,
+			}
+		}
+		out.write_all(b"\":")?;
+		match node {
+			nrofs::Node::File(i) => write!(out, "{}", tree.entry(i).unwrap().size())?,
+			nrofs::Node::Dir(d) => json(tree, d, out)?,
+		}
+	}
+	out.write_all(b"}")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn extract_path_normal() {
+		let p = extract_path(Path::new("out"), b"/a//b/c.txt").unwrap();
+		assert_eq!(p, Path::new("out/a/b/c.txt"));
+	}
+
+	#[test]
+	fn extract_path_rejects() {
+		for name in ["", "/", ".", "a/..", "../a", "a/./b"] {
+			assert_eq!(
+				extract_path(Path::new("out"), name.as_bytes()),
+				None,
+				"{:?}",
+				name
+			);
+		}
+		assert_eq!(extract_path(Path::new("out"), b"\xff"), None);
+	}
+
+	#[test]
+	fn json_escapes() {
+		let mut w = nrofs::Writer::default();
+		w.add(b"a\"b", b"123").unwrap();
+		w.add(b"d/\x01e", b"").unwrap();
+		w.add(b"d/f\\g", b"1").unwrap();
+		let mut img = io::Cursor::new(w.finish().unwrap());
+		let header = nrofs::Header::from_reader(&mut img).unwrap();
+		let tree = nrofs::Tree::new(&header, MAX_DEPTH, nrofs::reader_io(&mut img)).unwrap();
+		let mut out = Vec::new();
+		json(&tree, 0, &mut out).unwrap();
+		assert_eq!(
+			String::from_utf8(out).unwrap(),
+			r#"{"a\"b":3,"d":{"\u0001e":0,"f\\g":1}}"#
+		);
+	}
+
+	#[cfg(windows)]
+	#[test]
+	fn extract_path_rejects_windows() {
+		for name in ["C:", "C:/a", "a\\..\\..", "\\\\server\\share"] {
+			assert_eq!(
+				extract_path(Path::new("out"), name.as_bytes()),
+				None,
+				"{:?}",
+				name
+			);
+		}
+	}
+}
@@ -0,0 +1,49 @@
+//! Extracts images into a directory.
+
+use nrofs::Writer;
+use std::{fs, path::PathBuf, process::Command};
+
+/// A path for the directory `name` of the test, which doesn't exist yet.
+fn temp(name: &str) -> PathBuf {
+	let p = std::env::temp_dir().join(format!("nrofs-extract-{}-{}", std::process::id(), name));
+	let _ = fs::remove_dir_all(&p);
+	p
+}
+
+/// Extract `files` and return the directory they were extracted to.
+fn extract(files: &[(&str, &[u8])], name: &str, sparse: bool) -> PathBuf {
+	let mut w = Writer::default();
+	for (n, data) in files {
+		w.add(n.as_bytes(), data).unwrap();
+	}
+	let img = temp(&format!("{}.img", name));
+	fs::write(&img, w.finish().unwrap()).unwrap();
+	let dest = temp(name);
+	let mut cmd = Command::new(env!("CARGO_BIN_EXE_nrofs_cli"));
+	cmd.arg("extract").arg(&img).arg(&dest);
+	if sparse {
+		cmd.arg("--sparse");
+	}
+	let o = cmd.output().unwrap();
+	fs::remove_file(img).unwrap();
+	assert!(o.status.success(), "{}", String::from_utf8_lossy(&o.stderr));
+	dest
+}
+
+#[test]
+fn sparse() {
+	let zeros = vec![0; 1 << 20];
+	let mut tail = vec![0; 100_000];
+	tail.push(1);
+	let dest = extract(&[("zeros", &zeros), ("d/tail", &tail)], "sparse", true);
+	assert_eq!(fs::read(dest.join("zeros")).unwrap(), zeros);
+	assert_eq!(fs::read(dest.join("d/tail")).unwrap(), tail);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::MetadataExt;
+		let m = fs::metadata(dest.join("zeros")).unwrap();
+		assert_eq!(m.len(), zeros.len() as u64);
+		assert!(m.blocks() * 512 < m.len(), "{} blocks", m.blocks());
+	}
+	fs::remove_dir_all(dest).unwrap();
+}
@@ -0,0 +1,129 @@
+//! Runs `fsck` on damaged images.
+
+use nrofs::{Header, Writer};
+use std::{
+	fs,
+	io::Cursor,
+	path::PathBuf,
+	process::{Command, Output},
+};
+
+/// A path for the image `name` of the test, which doesn't exist yet.
+fn temp(name: &str) -> PathBuf {
+	let p = std::env::temp_dir().join(format!("nrofs-fsck-{}-{}", std::process::id(), name));
+	let _ = fs::remove_file(&p);
+	p
+}
+
+fn cli(args: &[&std::ffi::OsStr]) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_nrofs_cli"))
+		.args(args)
+		.output()
+		.unwrap()
+}
+
+fn fsck(img: &[u8], name: &str, repair: Option<&PathBuf>) -> (bool, String) {
+	let p = temp(name);
+	fs::write(&p, img).unwrap();
+	let mut args = vec!["fsck".as_ref(), p.as_os_str()];
+	if let Some(out) = repair {
+		args.push("--repair".as_ref());
+		args.push(out.as_os_str());
+	}
+	let o = cli(&args);
+	fs::remove_file(p).unwrap();
+	(o.status.success(), String::from_utf8(o.stdout).unwrap())
+}
+
+/// The name and data of every file in an image.
+fn files(img: &[u8]) -> Vec<(String, Vec<u8>)> {
+	let mut c = Cursor::new(img);
+	let h = Header::from_reader(&mut c).unwrap();
+	let mut buf = [0; 255];
+	h.iter_from_reader(&mut c.clone())
+		.map(|e| {
+			let e = e.unwrap();
+			let name = e.name_from_reader(&mut buf, &mut c).unwrap();
+			let (start, len) = e.data_extent(&h);
+			let data = img[start as usize..][..len as usize].to_vec();
+			(String::from_utf8(name.to_vec()).unwrap(), data)
+		})
+		.collect()
+}
+
+#[test]
+fn clean() {
+	let mut w = Writer::default();
+	w.add(b"a", b"1").unwrap();
+	let (ok, out) = fsck(&w.finish().unwrap(), "clean", None);
+	assert!(ok);
+	assert_eq!(out, "no problems found\n");
+}
+
+#[test]
+fn repair() {
+	let mut w = Writer::default();
+	w.set_metadata(b"comment", b"kept").unwrap();
+	for (name, data) in [("a", &b"first"[..]), ("b", b"second"), ("c", b"lost")] {
+		w.add(name.as_bytes(), data).unwrap();
+	}
+	let mut img = w.finish().unwrap();
+	// Swap the first two entries and point the data of the last one past the end.
+	let table = img[16..28].to_vec();
+	img.copy_within(28..40, 16);
+	img[28..40].copy_from_slice(&table);
+	img[44..48].copy_from_slice(&u32::MAX.to_le_bytes());
+
+	let out = temp("repaired");
+	let (ok, report) = fsck(&img, "damaged", Some(&out));
+	assert!(!ok);
+	assert!(
+		report.contains("entry 1: \"a\" is out of order"),
+		"{}",
+		report
+	);
+	assert!(
+		report.contains("entry 2: data points outside"),
+		"{}",
+		report
+	);
+
+	let repaired = fs::read(&out).unwrap();
+	assert_eq!(
+		files(&repaired),
+		[
+			("a".into(), b"first".to_vec()),
+			("b".into(), b"second".to_vec())
+		]
+	);
+	let mut c = Cursor::new(&repaired[..]);
+	let h = Header::from_reader(&mut c).unwrap();
+	let mut m = h.metadata(nrofs::reader_io(&mut c)).unwrap();
+	let (mut k, mut v) = ([0; 255], [0; 255]);
+	let mut kept = false;
+	while let Some(kv) = m.next(&mut k, &mut v) {
+		kept |= kv.unwrap() == (&b"comment"[..], &b"kept"[..]);
+	}
+	assert!(kept);
+
+	let (ok, _) = fsck(&repaired, "repaired-again", None);
+	assert!(ok);
+	// The output is never overwritten.
+	let (ok, _) = fsck(&img, "damaged-again", Some(&out));
+	assert!(!ok);
+	assert_eq!(fs::read(&out).unwrap(), repaired);
+	fs::remove_file(out).unwrap();
+}
+
+#[test]
+fn unreadable_metadata() {
+	let mut w = Writer::default();
+	w.remove_metadata(b"tool");
+	w.remove_metadata(b"version");
+	let mut img = w.finish().unwrap();
+	// Claim a metadata section the image doesn't have room for.
+	img[10] |= 1;
+	let (ok, report) = fsck(&img, "metadata", None);
+	assert!(!ok);
+	assert!(report.starts_with("metadata: "), "{}", report);
+}
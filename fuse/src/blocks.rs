@@ -0,0 +1,257 @@
+//! Cache of recently read blocks of the image.
+
+use super::parts::Parts;
+use std::{
+	collections::{BTreeMap, HashMap},
+	io,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+};
+
+/// Reads from an image through a cache of the least recently used blocks.
+///
+/// Blocks are aligned to their size, which needn't be the block size of the image. Reads of
+/// blocks which aren't cached are merged so a large read still goes to the image at once.
+pub struct BlockCache {
+	parts: Parts,
+	block_size: u64,
+	/// The maximum amount of blocks kept. 0 disables the cache.
+	capacity: usize,
+	lru: Mutex<Lru>,
+	/// Blocks served from the cache.
+	pub hits: AtomicU64,
+	/// Blocks read from the image.
+	pub misses: AtomicU64,
+	/// Reads of the image, counting a run of blocks read at once as one.
+	pub reads: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+	/// Each cached block by index, with the time it was last used.
+	blocks: HashMap<u64, (u64, Box<[u8]>)>,
+	/// The index of each cached block by the time it was last used.
+	by_use: BTreeMap<u64, u64>,
+	time: u64,
+}
+
+impl Lru {
+	fn get(&mut self, index: u64) -> Option<&[u8]> {
+		let (t, data) = self.blocks.get_mut(&index)?;
+		self.by_use.remove(t);
+		self.time += 1;
+		*t = self.time;
+		self.by_use.insert(self.time, index);
+		Some(data)
+	}
+
+	fn insert(&mut self, index: u64, data: Box<[u8]>, capacity: usize) {
+		while self.blocks.len() >= capacity {
+			let Some((_, i)) = self.by_use.pop_first() else {
+				break;
+			};
+			self.blocks.remove(&i);
+		}
+		self.time += 1;
+		self.by_use.insert(self.time, index);
+		if let Some((t, _)) = self.blocks.insert(index, (self.time, data)) {
+			self.by_use.remove(&t);
+		}
+	}
+}
+
+impl BlockCache {
+	/// Cache up to `capacity` blocks of `block_size` bytes.
+	pub fn new(parts: Parts, block_size: u64, capacity: usize) -> Self {
+		Self {
+			parts,
+			block_size,
+			capacity,
+			lru: Default::default(),
+			hits: Default::default(),
+			misses: Default::default(),
+			reads: Default::default(),
+		}
+	}
+
+	/// The length of the image.
+	pub fn len(&self) -> u64 {
+		self.parts.len()
+	}
+
+	/// Fill `buf` from offset `pos` in the image, using cached blocks where possible.
+	pub fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+		let end = pos + buf.len() as u64;
+		if self.capacity == 0 || buf.is_empty() || end > self.len() {
+			self.reads
+				.fetch_add(u64::from(!buf.is_empty()), Ordering::Relaxed);
+			return self.parts.read_exact_at(buf, pos);
+		}
+		let bs = self.block_size;
+		let copy = |buf: &mut [u8], index: u64, data: &[u8]| {
+			let start = (index * bs).max(pos);
+			let stop = (index * bs + data.len() as u64).min(end);
+			let d = &data[(start - index * bs) as usize..(stop - index * bs) as usize];
+			buf[(start - pos) as usize..(stop - pos) as usize].copy_from_slice(d);
+		};
+		let mut lru = self.lru.lock().unwrap();
+		let (mut i, last) = (pos / bs, (end - 1) / bs);
+		while i <= last {
+			if let Some(data) = lru.get(i) {
+				copy(buf, i, data);
+				self.hits.fetch_add(1, Ordering::Relaxed);
+				i += 1;
+				continue;
+			}
+			let mut j = i + 1;
+			while j <= last && !lru.blocks.contains_key(&j) {
+				j += 1;
+			}
+			let start = i * bs;
+			let mut run = vec![0; ((j * bs).min(self.len()) - start) as usize];
+			self.reads.fetch_add(1, Ordering::Relaxed);
+			self.parts.read_exact_at(&mut run, start)?;
+			self.misses.fetch_add(j - i, Ordering::Relaxed);
+			for (k, data) in (i..).zip(run.chunks(bs as usize)) {
+				copy(buf, k, data);
+				lru.insert(k, data.into(), self.capacity);
+			}
+			i = j;
+		}
+		Ok(())
+	}
+
+	/// Perform each [`nrofs::Op`] with positioned reads, starting at the beginning of the image.
+	pub fn io(&self) -> impl FnMut(nrofs::Op<'_>) -> io::Result<()> + '_ {
+		let mut pos = 0;
+		move |op| {
+			match op {
+				nrofs::Op::Seek(p) => pos = p,
+				nrofs::Op::Advance(d) => {
+					pos = pos
+						.checked_add_signed(d)
+						.ok_or(io::ErrorKind::InvalidInput)?
+				}
+				nrofs::Op::Read(b) => {
+					self.read_exact_at(b, pos)?;
+					pos += b.len() as u64;
+				}
+				nrofs::Op::Len(l) => *l = self.len(),
+			}
+			Ok(())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn lru(blocks: &[u64], capacity: usize) -> Lru {
+		let mut l = Lru::default();
+		for &i in blocks {
+			l.insert(i, vec![i as u8].into(), capacity);
+		}
+		l
+	}
+
+	fn cached(l: &Lru) -> Vec<u64> {
+		l.by_use.values().copied().collect()
+	}
+
+	#[test]
+	fn lru_evicts_least_recently_used() {
+		let mut l = lru(&[1, 2, 3], 3);
+		assert_eq!(l.get(1), Some(&[1][..]));
+		l.insert(4, vec![4].into(), 3);
+		assert_eq!(cached(&l), [3, 1, 4]);
+		assert_eq!(l.get(2), None);
+		l.insert(5, vec![5].into(), 3);
+		assert_eq!(cached(&l), [1, 4, 5]);
+		assert_eq!(l.blocks.len(), 3);
+	}
+
+	#[test]
+	fn lru_reinsert() {
+		let l = lru(&[1, 2, 1], 2);
+		assert_eq!(cached(&l), [2, 1]);
+		assert_eq!(l.blocks.len(), 2);
+	}
+
+	/// A cache of `capacity` blocks of 16 bytes over an image of `len` bytes.
+	fn cache(name: &str, len: usize, capacity: usize) -> (BlockCache, Vec<u8>) {
+		let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+		let p = std::env::temp_dir().join(format!("nrofs-{}-{}", name, std::process::id()));
+		std::fs::write(&p, &data).unwrap();
+		let parts = Parts::open(&p).unwrap();
+		std::fs::remove_file(p).unwrap();
+		(BlockCache::new(parts, 16, capacity), data)
+	}
+
+	fn counts(c: &BlockCache) -> [u64; 3] {
+		[&c.hits, &c.misses, &c.reads].map(|n| n.load(Ordering::Relaxed))
+	}
+
+	#[test]
+	fn hit_without_reading() {
+		let (c, data) = cache("hit", 100, 8);
+		let mut buf = [0; 40];
+		c.read_exact_at(&mut buf, 10).unwrap();
+		assert_eq!(buf, data[10..50]);
+		assert_eq!(counts(&c), [0, 4, 1]);
+		c.read_exact_at(&mut buf, 10).unwrap();
+		assert_eq!(buf, data[10..50]);
+		assert_eq!(counts(&c), [4, 4, 1]);
+		// Only the blocks which aren't cached yet are read, in one run.
+		let mut buf = [0; 50];
+		c.read_exact_at(&mut buf, 40).unwrap();
+		assert_eq!(buf, data[40..90]);
+		assert_eq!(counts(&c), [6, 6, 2]);
+		// The last block is short.
+		let mut buf = [0; 4];
+		c.read_exact_at(&mut buf, 96).unwrap();
+		c.read_exact_at(&mut buf, 96).unwrap();
+		assert_eq!(buf, data[96..]);
+		assert_eq!(counts(&c), [7, 7, 3]);
+	}
+
+	#[test]
+	fn threads() {
+		let (c, data) = cache("threads", 1000, 8);
+		let pos = |t: usize, i: usize| (t * 37 + i * 13) % 900;
+		std::thread::scope(|s| {
+			for t in 0..4 {
+				let (c, data) = (&c, &data);
+				s.spawn(move || {
+					for i in 0..200 {
+						let mut buf = [0; 50];
+						c.read_exact_at(&mut buf, pos(t, i) as u64).unwrap();
+						assert_eq!(buf, data[pos(t, i)..][..50]);
+					}
+				});
+			}
+		});
+		// Every block of every read is either a hit or a miss, regardless of the interleaving.
+		let blocks = (0..4)
+			.flat_map(|t| (0..200).map(move |i| pos(t, i)))
+			.map(|p| ((p + 49) / 16 - p / 16 + 1) as u64)
+			.sum::<u64>();
+		let [hits, misses, _] = counts(&c);
+		assert_eq!(hits + misses, blocks);
+		assert!(c.lru.lock().unwrap().blocks.len() <= 8);
+	}
+
+	#[test]
+	fn disabled() {
+		let (c, data) = cache("disabled", 100, 0);
+		let mut buf = [0; 10];
+		c.read_exact_at(&mut buf, 5).unwrap();
+		c.read_exact_at(&mut buf, 5).unwrap();
+		assert_eq!(buf, data[5..15]);
+		c.read_exact_at(&mut [], 5).unwrap();
+		assert_eq!(counts(&c), [0, 0, 2]);
+		assert!(c.read_exact_at(&mut buf, 95).is_err());
+	}
+}
@@ -1,196 +1,798 @@
+mod blocks;
+mod parts;
+
 use fuser::*;
+use nrofs::Node;
 use std::{
-	collections::BTreeMap,
+	collections::{BTreeMap, HashMap},
 	ffi::OsStr,
 	fs::File,
-	io::{Read, Seek},
+	io::{self, Read, Write},
 	os::unix::ffi::OsStrExt,
+	os::unix::io::{AsRawFd, FromRawFd},
+	sync::atomic::{AtomicU64, Ordering},
 	time::{Duration, UNIX_EPOCH},
 };
 
 const TTL: Duration = Duration::MAX;
+const MAX_DEPTH: usize = 1024;
+const READAHEAD: u32 = 1 << 20;
+/// The least amount of bytes cached together, so images with small blocks don't need to keep
+/// track of as many.
+const CACHE_LINE: u64 = 64 << 10;
+/// Inode of the file exposing the image, which no entry can have.
+const IMAGE_INO: u64 = u64::MAX;
+const IMAGE_NAME: &str = ".nrofs-image";
+const MIME_XATTR: &str = "user.mime_type";
+const STATS_XATTR: &str = "user.nrofs_stats";
+
+fn main() {
+	if let Err(e) = run() {
+		eprintln!("nrofs_fuse: {}", e);
+		std::process::exit(1);
+	}
+}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+	let (mut f, mut m) = (None, None);
+	let mut daemonize = false;
+	let mut o = Options::default();
 	let mut a = std::env::args().skip(1);
-	let f = a.next().ok_or("expected file path")?;
-	let m = a.next().ok_or("expected mount path")?;
-
-	fuser::mount2(
-		Fs::new(File::open(&f)?),
-		m,
-		&[MountOption::RO, MountOption::FSName("nrofs".into())],
-	)?;
+	while let Some(arg) = a.next() {
+		match &*arg {
+			"--max-read" => o.max_read = Some(a.next().ok_or("expected max read size")?.parse()?),
+			"--max-depth" => o.max_depth = a.next().ok_or("expected max path depth")?.parse()?,
+			"--recursive-size" => o.recursive_size = true,
+			"--entry-ttl" => o.entry_ttl = secs(a.next().ok_or("expected entry TTL")?)?,
+			"--attr-ttl" => o.attr_ttl = secs(a.next().ok_or("expected attribute TTL")?)?,
+			"--uid-map" => o.uid_map = id_map(&a.next().ok_or("expected uid map")?)?,
+			"--gid-map" => o.gid_map = id_map(&a.next().ok_or("expected gid map")?)?,
+			"--nfs-export" => o.nfs_export = true,
+			"--default-permissions" => o.default_permissions = true,
+			"--expose-image" => o.expose_image = true,
+			"--readahead" => o.readahead = a.next().ok_or("expected readahead size")?.parse()?,
+			"--block-cache-size" => {
+				o.block_cache_size = a.next().ok_or("expected block cache size")?.parse()?
+			}
+			"--daemonize" => daemonize = true,
+			"--guess-mime" => o.guess_mime = true,
+			"--stats" => o.stats = true,
+			_ if f.is_none() => f = Some(arg),
+			_ if m.is_none() => m = Some(arg),
+			_ => Err(format!("unexpected argument {:?}", arg))?,
+		}
+	}
+	let f = f.ok_or("expected file path")?;
+	let m = m.ok_or("expected mount path")?;
+
+	// Times never change, so there is no point in the kernel tracking access times.
+	let mut opts = vec![
+		MountOption::RO,
+		MountOption::NoAtime,
+		MountOption::FSName("nrofs".into()),
+	];
+	if let Some(n) = o.max_read {
+		opts.push(MountOption::CUSTOM(format!("max_read={}", n)));
+	}
+	if o.default_permissions {
+		opts.push(MountOption::DefaultPermissions);
+	}
+	let img = parts::Parts::open(f.as_ref()).map_err(|e| format!("{}: {}", f, e))?;
+	let fs = Fs::new(img, o).map_err(|e| format!("{}: {}", f, e))?;
+	let ready = daemonize.then(fork).transpose()?;
+
+	// Block the signals before the session thread is spawned so only `sigtimedwait` sees them.
+	let signals = unsafe {
+		let mut set = std::mem::zeroed();
+		libc::sigemptyset(&mut set);
+		libc::sigaddset(&mut set, libc::SIGINT);
+		libc::sigaddset(&mut set, libc::SIGTERM);
+		libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+		set
+	};
+	let session = fuser::spawn_mount2(fs, &m, &opts)
+		.map_err(|e| format!("failed to mount on {}: {}", m, e))?;
+	if let Some(mut ready) = ready {
+		ready.write_all(&[0])?;
+		let null = File::options().read(true).write(true).open("/dev/null")?;
+		for fd in 0..3 {
+			unsafe { libc::dup2(null.as_raw_fd(), fd) };
+		}
+	}
+
+	// Poll so an unmount from elsewhere, which ends the session, also ends the process.
+	let timeout = libc::timespec { tv_sec: 1, tv_nsec: 0 };
+	while !session.guard.is_finished() {
+		if unsafe { libc::sigtimedwait(&signals, std::ptr::null_mut(), &timeout) } > 0 {
+			break;
+		}
+	}
+	// `BackgroundSession::join` panics if the session failed, so unmount by dropping the rest
+	// of the session and report the error of the thread instead.
+	let guard = {
+		let s = session;
+		s.guard
+	};
+	guard.join().map_err(|_| "session thread panicked")??;
 	Ok(())
 }
 
+/// Fork into the background and return a pipe to report a successful mount on.
+///
+/// The parent exits once the child reports success or with status 1 if the child exits first.
+fn fork() -> io::Result<File> {
+	let mut fds = [0; 2];
+	if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let (rd, wr) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+	match unsafe { libc::fork() } {
+		-1 => Err(io::Error::last_os_error()),
+		0 => {
+			drop(rd);
+			unsafe { libc::setsid() };
+			Ok(wr)
+		}
+		_ => {
+			drop(wr);
+			let mounted = (&rd).read(&mut [0]).is_ok_and(|n| n == 1);
+			std::process::exit(if mounted { 0 } else { 1 })
+		}
+	}
+}
+
+fn secs(s: String) -> Result<Duration, std::num::ParseIntError> {
+	s.parse().map(Duration::from_secs)
+}
+
+/// Parse a list of `image_id:host_id` pairs separated by commas.
+fn id_map(s: &str) -> Result<BTreeMap<u32, u32>, Box<dyn std::error::Error>> {
+	s.split(',')
+		.map(|p| {
+			let (a, b) = p
+				.split_once(':')
+				.ok_or_else(|| format!("invalid id pair {:?}", p))?;
+			Ok((a.parse()?, b.parse()?))
+		})
+		.collect()
+}
+
+struct Options {
+	max_read: Option<u32>,
+	max_depth: usize,
+	/// Report the total size of all files in a directory instead of the amount of children.
+	recursive_size: bool,
+	/// How long the kernel may cache lookups.
+	///
+	/// fuser uses the same value for the attributes returned with the lookup.
+	entry_ttl: Duration,
+	/// How long the kernel may cache attributes returned by `getattr`.
+	attr_ttl: Duration,
+	/// Translation of the owner ids stored in the image to host ids.
+	///
+	/// Ids without a mapping are passed through as is.
+	uid_map: BTreeMap<u32, u32>,
+	gid_map: BTreeMap<u32, u32>,
+	/// Use dense inode numbers and a generation derived from the image.
+	///
+	/// Directories are numbered first, in order of their first appearance in the entry table,
+	/// followed by all files in table order. The same image always maps the same path to the
+	/// same inode and generation, which makes the mount suitable for re-exporting over NFS.
+	nfs_export: bool,
+	/// Let the kernel check access against the reported mode and owner.
+	default_permissions: bool,
+	/// Add a file with the contents of the entire image to the root directory.
+	///
+	/// An entry with the same name takes precedence.
+	expose_image: bool,
+	/// How many bytes to read at once when a file is read sequentially. 0 disables readahead.
+	readahead: u32,
+	/// How many bytes of recently read blocks of the image to keep. 0 disables the cache.
+	///
+	/// Blocks are cached in lines of 64 KiB or the block size if that is larger.
+	block_cache_size: u64,
+	/// Expose a MIME type guessed from the first bytes of each file as an extended attribute.
+	guess_mime: bool,
+	/// Expose counters of the work done as an extended attribute of the root directory.
+	stats: bool,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self {
+			max_read: None,
+			max_depth: MAX_DEPTH,
+			recursive_size: false,
+			entry_ttl: TTL,
+			attr_ttl: TTL,
+			uid_map: Default::default(),
+			gid_map: Default::default(),
+			nfs_export: false,
+			default_permissions: false,
+			expose_image: false,
+			readahead: READAHEAD,
+			block_cache_size: 0,
+			guess_mime: false,
+			stats: false,
+		}
+	}
+}
+
 struct Fs {
-	io: std::cell::RefCell<File>,
+	/// Only accessed with positioned reads, so `Fs` can be shared between threads.
+	io: blocks::BlockCache,
 	header: nrofs::Header,
-	dirs: Vec<BTreeMap<Box<[u8]>, Node>>,
+	tree: nrofs::Tree,
+	image_len: u64,
+	dir_sizes: Vec<u64>,
+	/// The amount of subdirectories of each directory.
+	subdirs: Vec<u32>,
+	generation: u64,
+	opts: Options,
+	handles: HashMap<u64, Handle>,
+	next_fh: u64,
+	/// MIME types guessed so far by entry index.
+	mime_types: HashMap<u32, &'static str>,
+	stats: Stats,
+}
+
+/// `Fs` is moved to the session thread and must stay shareable between threads.
+fn _assert_send_sync() {
+	fn assert<T: Send + Sync>() {}
+	assert::<Fs>();
+}
+
+/// Counters of the work done, which are atomic so they stay cheap if `Fs` is shared.
+#[derive(Default)]
+struct Stats {
+	/// Reads of open files served entirely from the readahead buffer.
+	readahead_hits: AtomicU64,
+	readahead_misses: AtomicU64,
+	bytes_served: AtomicU64,
+}
+
+impl Stats {
+	fn add(counter: &AtomicU64, n: u64) {
+		counter.fetch_add(n, Ordering::Relaxed);
+	}
+
+	fn to_text(&self, cache: &blocks::BlockCache) -> String {
+		let c = [
+			("image_reads", &cache.reads),
+			("readahead_hits", &self.readahead_hits),
+			("readahead_misses", &self.readahead_misses),
+			("block_cache_hits", &cache.hits),
+			("block_cache_misses", &cache.misses),
+			("bytes_served", &self.bytes_served),
+		];
+		c.iter()
+			.map(|(k, v)| format!("{} {}\n", k, v.load(Ordering::Relaxed)))
+			.collect()
+	}
+}
+
+/// State of an open file.
+struct Handle {
+	/// Offset in the image where the next read is expected if the file is read sequentially.
+	next: u64,
+	/// Offset in the image of the data in `buf`.
+	offset: u64,
+	buf: Vec<u8>,
 }
 
 impl Fs {
-	fn new(mut io: File) -> Self {
+	fn new(io: parts::Parts, opts: Options) -> Result<Self, Box<dyn std::error::Error>> {
+		let len = io.len();
+		let header =
+			nrofs::Header::load_with_len(|b| io.read_exact_at(b, 0), len).map_err(|e| {
+				use nrofs::ParseHeaderError::*;
+				match e {
+					_ if len == 0 => "not an nrofs image (empty file)".into(),
+					Other(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+						"not an nrofs image (too short for a header)".into()
+					}
+					BadMagic => "not an nrofs image (bad magic)".into(),
+					UnsupportedVersion { found, .. } => {
+						format!("unsupported nrofs version {}", found)
+					}
+					e => format!("invalid image: {}", e),
+				}
+			})?;
+		let bs = header.block_size().as_bytes();
+		// Both are powers of 2, so each line is made of whole blocks.
+		let line = bs.max(CACHE_LINE);
+		let lines = opts.block_cache_size / line;
+		if opts.block_cache_size > 0 && lines == 0 {
+			Err(format!("block cache size must be at least {} bytes", line))?
+		}
+		let io = blocks::BlockCache::new(io, line, lines as usize);
+		let tree = match nrofs::Tree::new(&header, opts.max_depth, io.io()) {
+			Ok(t) => t,
+			Err(nrofs::TreeError::TooDeep(i)) => {
+				let mut buf = [0; 255];
+				let name = header
+					.nth_name(i, &mut buf, io.io())
+					.ok_or("entry too deep is out of range")??;
+				Err(format!(
+					"{:?} exceeds the maximum path depth of {}",
+					String::from_utf8_lossy(name),
+					opts.max_depth
+				))?
+			}
+			Err(e) => Err(e)?,
+		};
 		let mut s = Self {
-			header: nrofs::Header::load(|b| io.read_exact(b)).unwrap(),
-			io: io.into(),
-			dirs: Default::default(),
+			header,
+			io,
+			tree,
+			image_len: len,
+			dir_sizes: Default::default(),
+			subdirs: Default::default(),
+			generation: 0,
+			opts,
+			handles: Default::default(),
+			next_fh: 0,
+			mime_types: Default::default(),
+			stats: Default::default(),
 		};
-		let mut dirs = vec![BTreeMap::default()];
-		let mut buf = [0; 255];
-		for (i, e) in s
-			.header
-			.iter(|o| s.do_io(o))
-			.map(Result::unwrap)
-			.enumerate()
-		{
-			let name = e.name(&mut buf, |o| s.do_io(o)).unwrap();
-			let mut di = 0;
-			let mut it = name
-				.split(|&c| c == b'/')
-				.filter(|p| !p.is_empty())
-				.peekable();
-			while let Some(p) = it.next() {
-				let l = dirs.len().try_into().unwrap();
-				let d = dirs[di as usize].entry(p.into());
-				if it.peek().is_some() {
-					// dir
-					di = match d.or_insert(Node::File(u32::MAX)) {
-						Node::Dir(n) => *n,
-						d @ Node::File(_) => {
-							*d = Node::Dir(l);
-							dirs.push(Default::default());
-							l
-						}
-					};
-				} else {
-					// file
-					d.or_insert_with(|| Node::File(i.try_into().unwrap()));
-				}
+		s.subdirs = (0..s.tree.dir_count() as u32)
+			.map(|n| {
+				let c = s.tree.children(n);
+				c.filter(|(_, n)| matches!(n, Node::Dir(_))).count() as u32
+			})
+			.collect();
+		if s.opts.recursive_size {
+			// Subdirectories always come after their parent.
+			s.dir_sizes = vec![0; s.tree.dir_count()];
+			for i in (0..s.tree.dir_count()).rev() {
+				let size = s.tree.children(i as u32).map(|(_, n)| s.size(n)).sum();
+				s.dir_sizes[i] = size;
 			}
 		}
-		s.dirs = dirs;
-		s
+		if s.opts.nfs_export {
+			s.generation = s.fingerprint()?;
+		}
+		Ok(s)
 	}
 
-	fn attr(&self, ty: FileType, size: u64, ino: u64) -> FileAttr {
-		let blksize = 1u32 << self.header.block_size();
+	fn attr(&self, node: Node) -> FileAttr {
+		let blksize = self.header.block_size().as_bytes() as u32;
+		let size = self.size(node);
+		let nlink = match node {
+			Node::File(_) => 1,
+			// One for the entry in the parent, one for "." and one for ".." of each subdirectory.
+			Node::Dir(n) => 2 + self.subdirs[n as usize],
+		};
 		FileAttr {
 			atime: UNIX_EPOCH,
 			mtime: UNIX_EPOCH,
 			ctime: UNIX_EPOCH,
 			crtime: UNIX_EPOCH,
 			perm: 0o777,
-			nlink: 1,
-			uid: 0,
-			gid: 0,
+			nlink,
+			// The image doesn't store owners, so everything belongs to id 0.
+			uid: *self.opts.uid_map.get(&0).unwrap_or(&0),
+			gid: *self.opts.gid_map.get(&0).unwrap_or(&0),
 			rdev: 0,
 			flags: 0,
-			kind: ty,
+			kind: to_ty(node),
 			size,
-			blocks: (size + u64::from(blksize) - 1) / u64::from(blksize),
-			ino,
+			// In units of 512 bytes, regardless of the block size of the image.
+			blocks: size.div_ceil(512),
+			ino: self.ino(node),
 			blksize,
 		}
 	}
 
+	fn image_attr(&self) -> FileAttr {
+		FileAttr {
+			kind: FileType::RegularFile,
+			size: self.image_len,
+			blocks: self.image_len.div_ceil(512),
+			nlink: 1,
+			ino: IMAGE_INO,
+			..self.attr(Node::Dir(0))
+		}
+	}
+
+	fn ino_attr(&self, ino: u64) -> Option<FileAttr> {
+		match self.entry(ino) {
+			Some(e) => Some(self.attr(e)),
+			None if ino == IMAGE_INO && self.is_image(0, IMAGE_NAME.as_bytes()) => {
+				Some(self.image_attr())
+			}
+			None => None,
+		}
+	}
+
+	/// The attributes of `name` in directory `parent` and how long the kernel may cache them.
+	fn lookup_attr(&self, parent: u64, name: &[u8]) -> Option<(Duration, FileAttr)> {
+		let n = match self.entry(parent) {
+			Some(Node::Dir(n)) => n,
+			_ => return None,
+		};
+		let a = match self.tree.child(n, name) {
+			Some(e) => self.attr(e),
+			None if self.is_image(n, name) => self.image_attr(),
+			None => return None,
+		};
+		Some((self.opts.entry_ttl, a))
+	}
+
+	/// The attributes of an inode and how long the kernel may cache them.
+	fn ino_attr_ttl(&self, ino: u64) -> Option<(Duration, FileAttr)> {
+		self.ino_attr(ino).map(|a| (self.opts.attr_ttl, a))
+	}
+
+	/// The entries of directory `n` after the one at `offset`, each with its own offset.
+	///
+	/// "." and ".." are at offsets 1 and 2 and the children follow from 3, so listing from
+	/// the offset of the last entry received continues right after it.
+	fn dir_entries(
+		&self,
+		n: u32,
+		offset: i64,
+	) -> impl Iterator<Item = (u64, i64, FileType, &OsStr)> + '_ {
+		let offset = usize::try_from(offset).unwrap_or(0);
+		let ino = self.ino(Node::Dir(n));
+		let dots = [(ino, 1, ".".as_ref()), (ino, 2, "..".as_ref())];
+		let dots = dots
+			.into_iter()
+			.map(|(i, o, k)| (i, o, FileType::Directory, k));
+		let start = offset.saturating_sub(2);
+		let children = (start as i64 + 3..)
+			.zip(self.tree.children_from(n, start))
+			.map(|(i, (k, v))| (self.ino(v), i, to_ty(v), OsStr::from_bytes(k)));
+		let len = self.tree.children(n).len();
+		let image = (offset <= len + 2 && self.is_image(n, IMAGE_NAME.as_bytes())).then_some((
+			IMAGE_INO,
+			len as i64 + 3,
+			FileType::RegularFile,
+			IMAGE_NAME.as_ref(),
+		));
+		dots.skip(offset).chain(children).chain(image)
+	}
+
+	/// The attributes to reply to `setattr` with, or `EROFS` if more than the times would
+	/// change.
+	fn set_attr(
+		&self,
+		ino: u64,
+		mode: Option<u32>,
+		uid: Option<u32>,
+		gid: Option<u32>,
+		size: Option<u64>,
+		flags: Option<u32>,
+	) -> Result<(Duration, FileAttr), i32> {
+		let a = self.ino_attr_ttl(ino).ok_or(libc::ENOENT)?;
+		if mode.or(uid).or(gid).or(flags).is_some() || size.is_some() {
+			return Err(libc::EROFS);
+		}
+		Ok(a)
+	}
+
+	/// The offset in the image and the size of the data of a file.
+	fn span(&self, ino: u64) -> Option<(u64, u64)> {
+		match self.entry(ino) {
+			Some(Node::File(n)) => {
+				let (start, len) = self.tree.entry(n).unwrap().data_extent(&self.header);
+				Some((start, len.into()))
+			}
+			None if ino == IMAGE_INO && self.is_image(0, IMAGE_NAME.as_bytes()) => {
+				Some((0, self.image_len))
+			}
+			_ => None,
+		}
+	}
+
+	/// Read `n` bytes at offset `pos` in the image for an open file ending at `end`.
+	///
+	/// Sequential reads fill a buffer of the readahead size which serves the reads after it.
+	fn read_handle(&mut self, fh: u64, pos: u64, n: usize, end: u64) -> std::io::Result<Vec<u8>> {
+		let h = match self.handles.get_mut(&fh) {
+			Some(h) if self.opts.readahead > 0 => h,
+			_ => {
+				let mut buf = vec![0; n];
+				self.io.read_exact_at(&mut buf, pos)?;
+				Stats::add(&self.stats.bytes_served, n as u64);
+				return Ok(buf);
+			}
+		};
+		let in_buf =
+			|h: &Handle| pos >= h.offset && pos + n as u64 <= h.offset + h.buf.len() as u64;
+		let hit = in_buf(h);
+		Stats::add(
+			if hit {
+				&self.stats.readahead_hits
+			} else {
+				&self.stats.readahead_misses
+			},
+			1,
+		);
+		if !hit && pos == h.next {
+			let len = u64::from(self.opts.readahead).max(n as u64).min(end - pos);
+			h.buf.resize(len as usize, 0);
+			self.io.read_exact_at(&mut h.buf, pos)?;
+			h.offset = pos;
+		}
+		h.next = pos + n as u64;
+		let buf = if in_buf(h) {
+			let i = (pos - h.offset) as usize;
+			h.buf[i..i + n].to_vec()
+		} else {
+			let mut buf = vec![0; n];
+			self.io.read_exact_at(&mut buf, pos)?;
+			buf
+		};
+		Stats::add(&self.stats.bytes_served, n as u64);
+		Ok(buf)
+	}
+
+	/// Read up to `size` bytes at `offset` in a file, returning the error to reply with.
+	fn read_file(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, i32> {
+		let (start, len) = self.span(ino).ok_or(libc::ENOENT)?;
+		let (offset, size) = read_range(len, offset, size).ok_or(libc::EINVAL)?;
+		self.read_handle(fh, start + offset, size as _, start + len)
+			.map_err(|_| libc::EIO)
+	}
+
+	fn mime_type(&mut self, index: u32) -> io::Result<&'static str> {
+		if let Some(m) = self.mime_types.get(&index) {
+			return Ok(m);
+		}
+		let (start, len) = self.tree.entry(index).unwrap().data_extent(&self.header);
+		let mut head = vec![0; len.min(512) as usize];
+		self.io.read_exact_at(&mut head, start)?;
+		let m = guess_mime(&head);
+		self.mime_types.insert(index, m);
+		Ok(m)
+	}
+
+	/// Whether `name` in directory `dir` refers to the file exposing the image.
+	fn is_image(&self, dir: u32, name: &[u8]) -> bool {
+		self.opts.expose_image
+			&& dir == 0
+			&& name == IMAGE_NAME.as_bytes()
+			&& self.tree.child(0, name).is_none()
+	}
+
 	fn entry(&self, ino: u64) -> Option<Node> {
-		let n = ino - 1;
-		if n & 1 << 63 != 0 {
-			(n ^ (1 << 63) < self.header.file_count().into()).then(|| Node::File(n as _))
+		if ino == FUSE_ROOT_ID {
+			return Some(Node::Dir(0));
+		}
+		let n = ino.checked_sub(1)?;
+		if self.opts.nfs_export {
+			let d = self.tree.dir_count() as u64;
+			if n < d {
+				Some(Node::Dir(n as _))
+			} else {
+				(n - d < self.header.file_count().into()).then_some(Node::File((n - d) as _))
+			}
+		} else if n & 1 << 63 != 0 {
+			(n ^ (1 << 63) < self.header.file_count().into()).then_some(Node::File(n as _))
 		} else {
-			(n < self.dirs.len() as u64).then(|| Node::Dir(n as _))
+			(n < self.tree.dir_count() as u64).then_some(Node::Dir(n as _))
+		}
+	}
+
+	fn ino(&self, node: Node) -> u64 {
+		if node == Node::Dir(0) {
+			return FUSE_ROOT_ID;
+		}
+		1 + match node {
+			Node::File(n) if self.opts.nfs_export => self.tree.dir_count() as u64 + u64::from(n),
+			Node::File(n) => u64::from(n) | 1 << 63,
+			Node::Dir(n) => n.into(),
+		}
+	}
+
+	/// FNV-1a hash of the header and entry table.
+	fn fingerprint(&self) -> Result<u64, std::io::Error> {
+		let mut h = 0xcbf2_9ce4_8422_2325u64;
+		let mut left = self.header.data_region_start();
+		let mut buf = [0; 4096];
+		let mut pos = 0;
+		while left > 0 {
+			let b = &mut buf[..left.min(4096) as usize];
+			self.io.read_exact_at(b, pos)?;
+			pos += b.len() as u64;
+			for &c in b.iter() {
+				h = (h ^ u64::from(c)).wrapping_mul(0x100_0000_01b3);
+			}
+			left -= b.len() as u64;
 		}
+		Ok(h)
 	}
 
-	fn do_io(&self, op: nrofs::Op) -> Result<(), std::io::Error> {
-		let mut io = self.io.borrow_mut();
-		match op {
-			nrofs::Op::Seek(p) => io.seek(std::io::SeekFrom::Start(p)).map(|_| ()),
-			nrofs::Op::Advance(p) => io.seek(std::io::SeekFrom::Current(p)).map(|_| ()),
-			nrofs::Op::Read(b) => io.read_exact(b),
+	fn size(&self, node: Node) -> u64 {
+		match node {
+			Node::File(n) => self.tree.entry(n).unwrap().size().into(),
+			Node::Dir(n) if self.opts.recursive_size => self.dir_sizes[n as usize],
+			Node::Dir(n) => self.tree.children(n).len() as _,
 		}
 	}
 }
 
-#[derive(Clone, Copy, Debug)]
-enum Node {
-	File(u32),
-	Dir(u32),
+/// The offset and amount of bytes to read for a request of `size` bytes at `offset` in a file
+/// of `len` bytes.
+///
+/// A reply shorter than requested is taken as end of file, so only the end of the file may
+/// shorten it. The offset is clamped to the end of the file and `None` is returned if it is
+/// negative.
+fn read_range(len: u64, offset: i64, size: u32) -> Option<(u64, u32)> {
+	let offset = u64::try_from(offset).ok()?.min(len);
+	let size = (len - offset).min(size.into()) as u32;
+	Some((offset, size))
 }
 
-impl Node {
-	fn to_ino(&self) -> u64 {
-		1 + match *self {
-			Self::File(n) => u64::from(n) | 1 << 63,
-			Self::Dir(n) => n.into(),
-		}
+/// Guess the MIME type of a file from its first bytes.
+fn guess_mime(head: &[u8]) -> &'static str {
+	const MAGIC: &[(&[u8], &str)] = &[
+		(b"\x89PNG\r\n\x1a\n", "image/png"),
+		(b"\xff\xd8\xff", "image/jpeg"),
+		(b"GIF87a", "image/gif"),
+		(b"GIF89a", "image/gif"),
+		(b"%PDF-", "application/pdf"),
+		(b"PK\x03\x04", "application/zip"),
+		(b"\x1f\x8b", "application/gzip"),
+		(b"\x7fELF", "application/x-executable"),
+	];
+	if let Some((_, m)) = MAGIC.iter().find(|(p, _)| head.starts_with(p)) {
+		return m;
+	}
+	if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+		return "image/webp";
 	}
+	// The head may end in the middle of a character.
+	match std::str::from_utf8(head) {
+		_ if head.is_empty() => "application/x-empty",
+		_ if head.contains(&0) => "application/octet-stream",
+		Ok(_) => "text/plain",
+		Err(e) if e.error_len().is_none() => "text/plain",
+		Err(_) => "application/octet-stream",
+	}
+}
 
-	fn to_ty(&self) -> FileType {
-		match self {
-			Self::File(_) => FileType::RegularFile,
-			Self::Dir(_) => FileType::Directory,
-		}
+/// Reply with the size of an extended attribute or its value if it fits in `size`.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+	match u32::try_from(value.len()) {
+		Ok(l) if size == 0 => reply.size(l),
+		Ok(l) if l <= size => reply.data(value),
+		_ => reply.error(libc::ERANGE),
 	}
+}
 
-	fn size(&self, fs: &Fs) -> u64 {
-		match *self {
-			Self::File(n) => fs.header.get(n, |o| fs.do_io(o)).unwrap().unwrap().size() as _,
-			Self::Dir(n) => fs.dirs[n as usize].len() as _,
-		}
+fn to_ty(node: Node) -> FileType {
+	match node {
+		Node::File(_) => FileType::RegularFile,
+		Node::Dir(_) => FileType::Directory,
 	}
 }
 
 impl Filesystem for Fs {
 	fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-		match self.entry(parent) {
-			Some(Node::Dir(n)) => {
-				if let Some(e) = self.dirs[n as usize].get(name.as_bytes()) {
-					reply.entry(&TTL, &self.attr(e.to_ty(), e.size(self), e.to_ino()), 0)
-				} else {
-					reply.error(libc::ENOENT)
-				}
-			}
-			_ => reply.error(libc::ENOENT),
+		match self.lookup_attr(parent, name.as_bytes()) {
+			Some((ttl, a)) => reply.entry(&ttl, &a, self.generation),
+			None => reply.error(libc::ENOENT),
 		}
 	}
 
 	fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-		match self.entry(ino) {
-			Some(e) => reply.attr(&TTL, &self.attr(e.to_ty(), e.size(self), e.to_ino())),
+		match self.ino_attr_ttl(ino) {
+			Some((ttl, a)) => reply.attr(&ttl, &a),
 			None => reply.error(libc::ENOENT),
 		}
 	}
 
+	/// Time updates are accepted and ignored so tools like `touch` don't fail.
+	fn setattr(
+		&mut self,
+		_req: &Request<'_>,
+		ino: u64,
+		mode: Option<u32>,
+		uid: Option<u32>,
+		gid: Option<u32>,
+		size: Option<u64>,
+		_atime: Option<TimeOrNow>,
+		_mtime: Option<TimeOrNow>,
+		_ctime: Option<std::time::SystemTime>,
+		_fh: Option<u64>,
+		_crtime: Option<std::time::SystemTime>,
+		_chgtime: Option<std::time::SystemTime>,
+		_bkuptime: Option<std::time::SystemTime>,
+		flags: Option<u32>,
+		reply: ReplyAttr,
+	) {
+		match self.set_attr(ino, mode, uid, gid, size, flags) {
+			Ok((ttl, a)) => reply.attr(&ttl, &a),
+			Err(e) => reply.error(e),
+		}
+	}
+
 	fn read(
 		&mut self,
 		_req: &Request,
 		ino: u64,
-		_fh: u64,
+		fh: u64,
 		offset: i64,
 		size: u32,
 		_flags: i32,
 		_lock: Option<u64>,
 		reply: ReplyData,
 	) {
-		let mut buf = [0; 1 << 16];
+		match self.read_file(ino, fh, offset, size) {
+			Ok(buf) => reply.data(&buf),
+			Err(e) => reply.error(e),
+		}
+	}
+
+	fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+		let (start, _) = match self.span(ino) {
+			Some(s) => s,
+			None => return reply.error(libc::ENOENT),
+		};
+		let fh = self.next_fh;
+		self.next_fh += 1;
+		let h = Handle { next: start, offset: 0, buf: Vec::new() };
+		self.handles.insert(fh, h);
+		reply.opened(fh, 0);
+	}
+
+	fn release(
+		&mut self,
+		_req: &Request<'_>,
+		_ino: u64,
+		fh: u64,
+		_flags: i32,
+		_lock_owner: Option<u64>,
+		_flush: bool,
+		reply: ReplyEmpty,
+	) {
+		self.handles.remove(&fh);
+		reply.ok();
+	}
+
+	fn getxattr(
+		&mut self,
+		_req: &Request<'_>,
+		ino: u64,
+		name: &OsStr,
+		size: u32,
+		reply: ReplyXattr,
+	) {
+		if self.ino_attr(ino).is_none() {
+			return reply.error(libc::ENOENT);
+		}
 		match self.entry(ino) {
-			Some(Node::File(n)) => {
-				let e = self.header.get(n, |o| self.do_io(o)).unwrap().unwrap();
-				let offt = i128::from(e.offset(&self.header)) + i128::from(offset);
-				let size = (e.size() as i128 - i128::from(offset))
-					.min(size as _)
-					.min(buf.len() as _)
-					.max(0);
-				self.io
-					.borrow_mut()
-					.seek(std::io::SeekFrom::Start(offt.try_into().unwrap()))
-					.unwrap();
-				self.io.borrow_mut().read(&mut buf[..size as _]).unwrap();
-				reply.data(&buf[..size as _]);
+			Some(Node::File(n)) if self.opts.guess_mime && name == MIME_XATTR => {
+				match self.mime_type(n) {
+					Ok(m) => reply_xattr(reply, size, m.as_bytes()),
+					Err(_) => reply.error(libc::EIO),
+				}
+			}
+			Some(Node::Dir(0)) if self.opts.stats && name == STATS_XATTR => {
+				reply_xattr(reply, size, self.stats.to_text(&self.io).as_bytes())
+			}
+			_ => reply.error(libc::ENODATA),
+		}
+	}
+
+	fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+		if self.ino_attr(ino).is_none() {
+			return reply.error(libc::ENOENT);
+		}
+		match self.entry(ino) {
+			Some(Node::File(_)) if self.opts.guess_mime => {
+				let mut names = MIME_XATTR.as_bytes().to_vec();
+				names.push(0);
+				reply_xattr(reply, size, &names)
+			}
+			Some(Node::Dir(0)) if self.opts.stats => {
+				let mut names = STATS_XATTR.as_bytes().to_vec();
+				names.push(0);
+				reply_xattr(reply, size, &names)
 			}
-			_ => reply.error(libc::ENOENT),
+			_ => reply_xattr(reply, size, &[]),
 		}
 	}
 
@@ -199,7 +801,7 @@ impl Filesystem for Fs {
 		_req: &Request<'_>,
 		ino: u64,
 		_fh: u64,
-		mut offset: i64,
+		offset: i64,
 		mut reply: ReplyDirectory,
 	) {
 		let n = if let Some(Node::Dir(n)) = self.entry(ino) {
@@ -208,31 +810,417 @@ impl Filesystem for Fs {
 			reply.error(libc::ENOENT);
 			return;
 		};
-
-		if offset == 0 {
-			if reply.add(Node::Dir(n).to_ino(), 1, FileType::Directory, ".") {
-				return reply.ok();
+		for (ino, offset, ty, name) in self.dir_entries(n, offset) {
+			if reply.add(ino, offset, ty, name) {
+				break;
 			}
-			offset += 1;
 		}
+		reply.ok();
+	}
+}
 
-		if offset == 1 {
-			if reply.add(Node::Dir(n).to_ino(), 2, FileType::Directory, "..") {
-				return reply.ok();
-			}
-			offset += 1;
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn read_range_last_byte() {
+		assert_eq!(read_range(10, 9, 4096), Some((9, 1)));
+	}
+
+	#[test]
+	fn read_range_at_end() {
+		assert_eq!(read_range(10, 10, 4096), Some((10, 0)));
+		assert_eq!(read_range(10, i64::MAX, 4096), Some((10, 0)));
+	}
+
+	#[test]
+	fn read_range_negative() {
+		assert_eq!(read_range(10, -1, 1), None);
+		assert_eq!(read_range(10, i64::MIN, u32::MAX), None);
+	}
+
+	#[test]
+	fn read_range_huge_size() {
+		assert_eq!(read_range(10, 0, u32::MAX), Some((0, 10)));
+		let len = u64::from(u32::MAX) * 4;
+		assert_eq!(read_range(len, 5, u32::MAX), Some((5, u32::MAX)));
+		assert_eq!(
+			read_range(len, (len - 1) as i64, u32::MAX),
+			Some((len - 1, 1))
+		);
+	}
+
+	/// Load an image from a temporary file, without mounting it.
+	fn open(name: &str, img: &[u8], opts: Options) -> Result<Fs, Box<dyn std::error::Error>> {
+		let p = std::env::temp_dir().join(format!("nrofs-{}-{}", name, std::process::id()));
+		std::fs::write(&p, img).unwrap();
+		let io = parts::Parts::open(&p).unwrap();
+		std::fs::remove_file(p).unwrap();
+		Fs::new(io, opts)
+	}
+
+	/// Load `files` as an image, without mounting it.
+	fn fs(name: &str, files: &[(&str, &[u8])], opts: Options) -> Fs {
+		let mut w = nrofs::Writer::default();
+		for (n, data) in files {
+			w.add(n.as_bytes(), data).unwrap();
 		}
+		open(name, &w.finish().unwrap(), opts).unwrap()
+	}
 
-		for (i, (k, v)) in self.dirs[n as usize]
-			.iter()
-			.enumerate()
-			.skip((offset - 2) as _)
-		{
-			if reply.add((i + 2) as _, (i + 3) as _, v.to_ty(), OsStr::from_bytes(k)) {
-				break;
-			}
+	#[test]
+	fn read_max_read() {
+		let data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+		let opts = Options { max_read: Some(4096), ..Default::default() };
+		let mut fs = fs("max-read", &[("a", &data)], opts);
+		let ino = fs.ino(Node::File(0));
+		assert_eq!(fs.read_file(ino, 0, 0, 4096).unwrap(), data[..4096]);
+		assert_eq!(fs.read_file(ino, 0, 4096, 4096).unwrap(), data[4096..8192]);
+		// The limit is up to the kernel, so a larger request is still served in full.
+		assert_eq!(fs.read_file(ino, 0, 0, 8192).unwrap(), data[..8192]);
+		assert_eq!(fs.read_file(ino, 0, 5000, 1 << 20).unwrap(), data[5000..]);
+	}
+
+	#[test]
+	fn too_deep() {
+		let mut w = nrofs::Writer::default();
+		w.add(b"a/b", b"").unwrap();
+		w.add(b"a/b/c", b"").unwrap();
+		let opts = Options { max_depth: 2, ..Default::default() };
+		let e = open("too-deep", &w.finish().unwrap(), opts).err().unwrap();
+		assert_eq!(
+			e.to_string(),
+			"\"a/b/c\" exceeds the maximum path depth of 2"
+		);
+	}
+
+	#[test]
+	fn recursive_size() {
+		let files: &[(&str, &[u8])] = &[("a/b/c", &[0; 10]), ("a/d", &[0; 5]), ("e", &[0; 7])];
+		let size = |fs: &Fs, path: &str| fs.attr(fs.tree.lookup(path.as_bytes()).unwrap()).size;
+		let opts = Options { recursive_size: true, ..Default::default() };
+		let r = fs("recursive-size", files, opts);
+		assert_eq!(
+			[size(&r, ""), size(&r, "a"), size(&r, "a/b"), size(&r, "e")],
+			[22, 15, 10, 7]
+		);
+		// Without the option a directory reports its amount of children.
+		let c = fs("child-count", files, Options::default());
+		assert_eq!([size(&c, ""), size(&c, "a"), size(&c, "a/b")], [2, 2, 1]);
+	}
+
+	#[test]
+	fn truncated_image() {
+		let mut w = nrofs::Writer::default();
+		w.add(b"a", b"1").unwrap();
+		let img = w.finish().unwrap();
+		let e = |img: &[u8]| {
+			open("truncated", img, Options::default())
+				.err()
+				.unwrap()
+				.to_string()
+		};
+		assert_eq!(
+			e(&img[..20]),
+			"invalid image: image too short for header or entry table"
+		);
+		assert_eq!(e(&img[..10]), "not an nrofs image (too short for a header)");
+		assert_eq!(e(&[]), "not an nrofs image (empty file)");
+	}
+
+	#[test]
+	fn ttl() {
+		let opts = Options {
+			entry_ttl: Duration::from_secs(5),
+			attr_ttl: Duration::from_secs(7),
+			expose_image: true,
+			..Default::default()
+		};
+		let fs = fs("ttl", &[("a", b"")], opts);
+		let ttl = |r: Option<(Duration, FileAttr)>| r.unwrap().0.as_secs();
+		assert_eq!(ttl(fs.lookup_attr(FUSE_ROOT_ID, b"a")), 5);
+		assert_eq!(ttl(fs.lookup_attr(FUSE_ROOT_ID, IMAGE_NAME.as_bytes())), 5);
+		assert_eq!(ttl(fs.ino_attr_ttl(fs.ino(Node::File(0)))), 7);
+		assert_eq!(ttl(fs.ino_attr_ttl(IMAGE_INO)), 7);
+		assert!(fs.lookup_attr(FUSE_ROOT_ID, b"b").is_none());
+	}
+
+	#[test]
+	fn uid_map() {
+		let opts = Options {
+			uid_map: id_map("0:1000,5:6").unwrap(),
+			gid_map: id_map("0:2000").unwrap(),
+			expose_image: true,
+			..Default::default()
+		};
+		let m = fs("id-map", &[("d/a", b"")], opts);
+		let ids = |a: FileAttr| (a.uid, a.gid);
+		assert_eq!(ids(m.ino_attr_ttl(FUSE_ROOT_ID).unwrap().1), (1000, 2000));
+		assert_eq!(
+			ids(m.lookup_attr(FUSE_ROOT_ID, b"d").unwrap().1),
+			(1000, 2000)
+		);
+		assert_eq!(ids(m.attr(m.tree.lookup(b"d/a").unwrap())), (1000, 2000));
+		assert_eq!(ids(m.ino_attr_ttl(IMAGE_INO).unwrap().1), (1000, 2000));
+		// Without a mapping the id stored in the image is passed through.
+		let p = fs("id-map-none", &[("a", b"")], Options::default());
+		assert_eq!(ids(p.attr(Node::File(0))), (0, 0));
+	}
+
+	#[test]
+	fn blocks() {
+		let fs = fs(
+			"blocks",
+			&[("a", &[0; 4097]), ("b", b"")],
+			Options::default(),
+		);
+		assert_eq!(fs.attr(Node::File(0)).blocks, 9);
+		assert_eq!(fs.attr(Node::File(1)).blocks, 0);
+		assert_eq!(fs.attr(Node::File(0)).blksize, 4096);
+	}
+
+	#[test]
+	fn nfs_export_stable() {
+		let mut w = nrofs::Writer::default();
+		for n in ["a/b", "a/c/d", "e", "f/g"] {
+			w.add(n.as_bytes(), n.as_bytes()).unwrap();
 		}
+		let img = w.finish().unwrap();
+		let opts = || Options { nfs_export: true, ..Default::default() };
+		let x = open("nfs-x", &img, opts()).unwrap();
+		let y = open("nfs-y", &img, opts()).unwrap();
+		assert_ne!(x.generation, 0);
+		assert_eq!(x.generation, y.generation);
+		for p in ["a", "a/b", "a/c", "a/c/d", "e", "f", "f/g"] {
+			let ino = |fs: &Fs| fs.ino(fs.tree.lookup(p.as_bytes()).unwrap());
+			assert_eq!(ino(&x), ino(&y));
+			assert_eq!(x.entry(ino(&x)), x.tree.lookup(p.as_bytes()));
+		}
+		// Directories are numbered first, densely.
+		let max = |fs: &Fs| fs.tree.dir_count() as u64 + u64::from(fs.header.file_count());
+		assert_eq!(x.ino(Node::File(3)), max(&x));
+		assert_eq!(x.entry(max(&x) + 1), None);
+	}
 
-		reply.ok();
+	#[test]
+	fn nlink() {
+		let files: &[(&str, &[u8])] = &[("d/x/a", b""), ("d/y", b""), ("d/z/b", b""), ("e", b"")];
+		let fs = fs("nlink", files, Options::default());
+		let nlink = |p: &str| fs.attr(fs.tree.lookup(p.as_bytes()).unwrap()).nlink;
+		assert_eq!(nlink("d"), 4);
+		assert_eq!(nlink(""), 3);
+		assert_eq!(nlink("d/x"), 2);
+		assert_eq!(nlink("e"), 1);
+	}
+
+	#[test]
+	fn root_attr() {
+		for nfs_export in [false, true] {
+			let opts = Options { nfs_export, ..Default::default() };
+			let fs = fs("root-attr", &[("a", b"")], opts);
+			let (_, a) = fs.ino_attr_ttl(FUSE_ROOT_ID).unwrap();
+			assert_eq!((a.ino, a.kind), (1, FileType::Directory));
+			assert_eq!(fs.entry(a.ino), Some(Node::Dir(0)));
+		}
+	}
+
+	#[test]
+	fn expose_image() {
+		let mut w = nrofs::Writer::default();
+		w.add(b"a", b"hello").unwrap();
+		let img = w.finish().unwrap();
+		let opts = Options { expose_image: true, ..Default::default() };
+		let mut exposed = open("expose-image", &img, opts).unwrap();
+		let (_, a) = exposed
+			.lookup_attr(FUSE_ROOT_ID, IMAGE_NAME.as_bytes())
+			.unwrap();
+		assert_eq!((a.ino, a.size), (IMAGE_INO, img.len() as u64));
+		assert_eq!(exposed.read_file(IMAGE_INO, 0, 0, u32::MAX).unwrap(), img);
+		// Without the option there is no such file.
+		let mut hidden = open("hidden-image", &img, Options::default()).unwrap();
+		assert!(hidden
+			.lookup_attr(FUSE_ROOT_ID, IMAGE_NAME.as_bytes())
+			.is_none());
+		assert_eq!(hidden.read_file(IMAGE_INO, 0, 0, 1), Err(libc::ENOENT));
+		// An entry of the same name takes precedence.
+		let opts = Options { expose_image: true, ..Default::default() };
+		let shadow = fs("shadowed-image", &[(IMAGE_NAME, b"mine")], opts);
+		let (_, a) = shadow
+			.lookup_attr(FUSE_ROOT_ID, IMAGE_NAME.as_bytes())
+			.unwrap();
+		assert_eq!((a.ino, a.size), (shadow.ino(Node::File(0)), 4));
+		assert!(shadow.ino_attr(IMAGE_INO).is_none());
+	}
+
+	#[test]
+	fn readdir_resume() {
+		let names = (0..100_000)
+			.map(|i| format!("{:06}", i))
+			.collect::<Vec<_>>();
+		let mut w = nrofs::Writer::default();
+		for n in &names {
+			w.add(n.as_bytes(), b"").unwrap();
+		}
+		let opts = Options { expose_image: true, ..Default::default() };
+		let fs = open("readdir", &w.finish().unwrap(), opts).unwrap();
+		// Continue from the last entry of each batch, as the kernel does.
+		let (mut seen, mut offset) = (Vec::new(), 0);
+		loop {
+			let batch = fs.dir_entries(0, offset).take(1000).collect::<Vec<_>>();
+			let Some(last) = batch.last() else { break };
+			offset = last.1;
+			seen.extend(batch.iter().map(|e| e.3.to_str().unwrap().to_string()));
+		}
+		let expect = [".", ".."]
+			.into_iter()
+			.chain(names.iter().map(|n| &**n))
+			.chain([IMAGE_NAME]);
+		assert!(seen.iter().map(|n| &**n).eq(expect));
+		assert_eq!(fs.dir_entries(0, offset).count(), 0);
+	}
+
+	#[test]
+	fn lookup_without_io() {
+		let files: &[(&str, &[u8])] = &[("a/b", b"1"), ("a/c/d", b"22"), ("e", b"")];
+		let opts = Options { recursive_size: true, expose_image: true, ..Default::default() };
+		let fs = fs("lookup-io", files, opts);
+		fs.io.reads.store(0, Ordering::Relaxed);
+		let a = fs.lookup_attr(FUSE_ROOT_ID, b"a").unwrap().1;
+		let c = fs.lookup_attr(a.ino, b"c").unwrap().1;
+		assert_eq!(fs.lookup_attr(c.ino, b"d").unwrap().1.size, 2);
+		assert!(fs.lookup_attr(a.ino, b"x").is_none());
+		assert!(fs
+			.lookup_attr(FUSE_ROOT_ID, IMAGE_NAME.as_bytes())
+			.is_some());
+		fs.ino_attr_ttl(c.ino).unwrap();
+		assert_eq!(stat(&fs, "image_reads"), 0);
+	}
+
+	#[test]
+	fn setattr() {
+		let opts = Options { attr_ttl: Duration::from_secs(3), ..Default::default() };
+		let fs = fs("setattr", &[("a", b"1")], opts);
+		let ino = fs.ino(Node::File(0));
+		// Only times are set, as by `touch`.
+		let (ttl, a) = fs.set_attr(ino, None, None, None, None, None).unwrap();
+		assert_eq!((ttl.as_secs(), a.ino, a.size), (3, ino, 1));
+		let changes = [
+			(Some(0o644), None, None),
+			(None, Some(1), None),
+			(None, None, Some(0)),
+		];
+		for (mode, uid, size) in changes {
+			let r = fs.set_attr(ino, mode, uid, None, size, None);
+			assert_eq!(r.err(), Some(libc::EROFS));
+			let r = fs.set_attr(FUSE_ROOT_ID, mode, uid, None, size, None);
+			assert_eq!(r.err(), Some(libc::EROFS));
+		}
+		let r = fs.set_attr(ino + 1, None, None, None, None, None);
+		assert_eq!(r.err(), Some(libc::ENOENT));
+	}
+
+	#[test]
+	fn guess_mime_magic() {
+		assert_eq!(guess_mime(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+		assert_eq!(guess_mime(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+		assert_eq!(guess_mime(b"RIFF\0\0\0\0WAVE"), "application/octet-stream");
+		assert_eq!(guess_mime(b""), "application/x-empty");
+	}
+
+	#[test]
+	fn guess_mime_fallback() {
+		assert_eq!(guess_mime(b"hello\nworld"), "text/plain");
+		// A character cut off at the end of the head is still text.
+		assert_eq!(
+			guess_mime("caf\u{e9}".as_bytes().split_last().unwrap().1),
+			"text/plain"
+		);
+		assert_eq!(guess_mime(b"text\0"), "application/octet-stream");
+		assert_eq!(guess_mime(b"\xff\xfe\xfd"), "application/octet-stream");
+	}
+
+	#[test]
+	fn mime_type_xattr() {
+		let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+		let opts = Options { guess_mime: true, ..Default::default() };
+		let mut fs = fs("mime", &[("a.png", png), ("b", b"text")], opts);
+		fs.io.reads.store(0, Ordering::Relaxed);
+		assert_eq!(fs.mime_type(0).unwrap(), "image/png");
+		assert_eq!(fs.mime_type(0).unwrap(), "image/png");
+		assert_eq!(fs.mime_type(1).unwrap(), "text/plain");
+		// Each file is only read once.
+		assert_eq!(stat(&fs, "image_reads"), 2);
+	}
+
+	#[test]
+	fn block_cache_lines() {
+		let mut w = nrofs::Writer::default();
+		w.add(b"a", &[1; 10_000]).unwrap();
+		let img = w.finish().unwrap();
+		let opts = Options { block_cache_size: CACHE_LINE, ..Default::default() };
+		let mut fs = open("cache-lines", &img, opts).unwrap();
+		// The whole image fits in one line, which loading the tree already read.
+		assert_eq!(stat(&fs, "block_cache_misses"), 1);
+		fs.read_file(fs.ino(Node::File(0)), 0, 0, 10_000).unwrap();
+		assert_eq!(stat(&fs, "block_cache_misses"), 1);
+		let opts = Options { block_cache_size: CACHE_LINE - 1, ..Default::default() };
+		let e = open("cache-small", &img, opts).err().unwrap();
+		assert_eq!(
+			e.to_string(),
+			"block cache size must be at least 65536 bytes"
+		);
+	}
+
+	fn stat(fs: &Fs, counter: &str) -> u64 {
+		let text = fs.stats.to_text(&fs.io);
+		let line = text.lines().find(|l| l.split(' ').next() == Some(counter));
+		line.unwrap().split(' ').nth(1).unwrap().parse().unwrap()
+	}
+
+	#[test]
+	fn stats_text() {
+		let s = Stats::default();
+		Stats::add(&s.bytes_served, 7);
+		Stats::add(&s.bytes_served, 5);
+		Stats::add(&s.readahead_hits, 1);
+		let fs = fs("stats", &[], Options::default());
+		assert_eq!(
+			s.to_text(&fs.io),
+			"image_reads 0\nreadahead_hits 1\nreadahead_misses 0\nblock_cache_hits 0\n\
+			 block_cache_misses 0\nbytes_served 12\n"
+		);
+	}
+
+	#[test]
+	fn bytes_served_without_handle() {
+		let mut fs = fs("served", &[("a", b"hello world")], Options::default());
+		let (start, len) = fs.span(fs.ino(Node::File(0))).unwrap();
+		fs.io.reads.store(0, Ordering::Relaxed);
+		assert_eq!(fs.read_handle(0, start, 5, start + len).unwrap(), b"hello");
+		assert_eq!(
+			fs.read_handle(0, start + 6, 5, start + len).unwrap(),
+			b"world"
+		);
+		assert_eq!(stat(&fs, "bytes_served"), 10);
+		assert_eq!(stat(&fs, "image_reads"), 2);
+	}
+
+	#[test]
+	fn bytes_served_readahead() {
+		let mut fs = fs("readahead", &[("a", b"hello world")], Options::default());
+		let (start, len) = fs.span(fs.ino(Node::File(0))).unwrap();
+		let h = Handle { next: start, offset: 0, buf: Vec::new() };
+		fs.handles.insert(0, h);
+		fs.io.reads.store(0, Ordering::Relaxed);
+		assert_eq!(fs.read_handle(0, start, 6, start + len).unwrap(), b"hello ");
+		assert_eq!(
+			fs.read_handle(0, start + 6, 5, start + len).unwrap(),
+			b"world"
+		);
+		assert_eq!(stat(&fs, "bytes_served"), 11);
+		assert_eq!(stat(&fs, "image_reads"), 1);
+		assert_eq!(stat(&fs, "readahead_hits"), 1);
+		assert_eq!(stat(&fs, "readahead_misses"), 1);
 	}
 }
@@ -0,0 +1,152 @@
+//! Images split over several numbered files.
+
+use std::{
+	ffi::OsString,
+	fs::File,
+	io,
+	os::unix::{
+		ffi::{OsStrExt, OsStringExt},
+		fs::FileExt,
+	},
+	path::{Path, PathBuf},
+};
+
+/// One or more files which together form an image, accessed with positioned reads.
+///
+/// Offsets are absolute in the image and are translated to a part and an offset within it.
+pub struct Parts {
+	/// Each part with the offset in the image at which it starts.
+	files: Vec<(u64, File)>,
+	len: u64,
+}
+
+impl Parts {
+	/// Open the image at `path`.
+	///
+	/// If the path ends in `.000` the image is split and `.001`, `.002` and so on are opened
+	/// after it until one doesn't exist.
+	pub fn open(path: &Path) -> io::Result<Self> {
+		let mut s = Self { files: Vec::new(), len: 0 };
+		s.push(File::open(path)?)?;
+		if let Some(base) = path.as_os_str().as_bytes().strip_suffix(b".000") {
+			for i in 1.. {
+				let mut p = base.to_vec();
+				p.extend_from_slice(format!(".{:03}", i).as_bytes());
+				let f = match File::open(PathBuf::from(OsString::from_vec(p))) {
+					Ok(f) => f,
+					Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+					Err(e) => return Err(e),
+				};
+				s.push(f)?;
+			}
+		}
+		Ok(s)
+	}
+
+	fn push(&mut self, f: File) -> io::Result<()> {
+		let len = f.metadata()?.len();
+		self.files.push((self.len, f));
+		self.len += len;
+		Ok(())
+	}
+
+	/// The combined length of all parts.
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+
+	/// Fill `buf` from offset `pos` in the image, reading from as many parts as needed.
+	pub fn read_exact_at(&self, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+		let mut i = self
+			.files
+			.partition_point(|(s, _)| *s <= pos)
+			.saturating_sub(1);
+		while !buf.is_empty() {
+			let (start, f) = self
+				.files
+				.get(i)
+				.filter(|_| pos < self.len)
+				.ok_or_else(|| {
+					io::Error::new(io::ErrorKind::UnexpectedEof, "read past end of image")
+				})?;
+			let end = self.files.get(i + 1).map_or(self.len, |(s, _)| *s);
+			let n = (end - pos).min(buf.len() as u64) as usize;
+			let (b, rest) = buf.split_at_mut(n);
+			f.read_exact_at(b, pos - start)?;
+			buf = rest;
+			pos += n as u64;
+			i += 1;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::ffi::OsStr;
+
+	/// A new empty directory for the test `name`.
+	fn temp_dir(name: &str) -> PathBuf {
+		let d = std::env::temp_dir().join(format!("nrofs-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&d);
+		std::fs::create_dir(&d).unwrap();
+		d
+	}
+
+	/// Split `data` into parts of the given sizes at `base.000`, `base.001` and so on.
+	fn split(base: &Path, data: &[u8], sizes: &[usize]) -> PathBuf {
+		let mut rest = data;
+		for (i, &n) in sizes.iter().enumerate() {
+			let (part, r) = rest.split_at(n);
+			let mut p = base.as_os_str().to_owned();
+			p.push(format!(".{:03}", i));
+			std::fs::write(p, part).unwrap();
+			rest = r;
+		}
+		assert!(rest.is_empty());
+		let mut p = base.as_os_str().to_owned();
+		p.push(".000");
+		p.into()
+	}
+
+	#[test]
+	fn read_across_parts() {
+		let dir = temp_dir("parts");
+		let data = (0..100).collect::<Vec<u8>>();
+		let parts = Parts::open(&split(&dir.join("img"), &data, &[10, 0, 25, 1, 64])).unwrap();
+		assert_eq!(parts.len(), 100);
+		for start in 0..data.len() {
+			for end in start..=data.len() {
+				let mut buf = vec![0; end - start];
+				parts.read_exact_at(&mut buf, start as u64).unwrap();
+				assert_eq!(buf, data[start..end], "{}..{}", start, end);
+			}
+		}
+		assert!(parts.read_exact_at(&mut [0; 2], 99).is_err());
+		assert!(parts.read_exact_at(&mut [0], 100).is_err());
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn single_file() {
+		let dir = temp_dir("single");
+		let p = dir.join("img.001");
+		std::fs::write(&p, b"abc").unwrap();
+		std::fs::write(dir.join("img.002"), b"def").unwrap();
+		let parts = Parts::open(&p).unwrap();
+		assert_eq!(parts.len(), 3);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn non_utf8_path() {
+		let dir = temp_dir("non-utf8");
+		let base = dir.join(OsStr::from_bytes(b"img\xff"));
+		let parts = Parts::open(&split(&base, b"abcdef", &[2, 4])).unwrap();
+		let mut buf = [0; 6];
+		parts.read_exact_at(&mut buf, 0).unwrap();
+		assert_eq!(&buf, b"abcdef");
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -5,30 +5,41 @@ from struct import pack, unpack
 from os import path
 from pathlib import Path
 import os
+import socket
+import time
 
 MAGIC = b"NrRdOnly"
 VERSION = 0
+FLAG_METADATA = 1 << 0
 
-def write_header(f, block_size, file_count):
-    f.write(pack("<8sBBxxI", MAGIC, VERSION, block_size, file_count))
+def write_header(f, block_size, file_count, flags=0):
+    f.write(pack("<8sBBBxI", MAGIC, VERSION, block_size, flags, file_count))
 
 def write_entry(f, filename_addr, block_addr, file_size):
     f.write(pack("<III", filename_addr, block_addr, file_size))
 
 def write_string(f, s):
+    f.write(encode_string(s))
+
+def encode_string(s) -> bytes:
     s = s.encode('utf-8')
     if len(s) > 255:
         raise StringTooLargeException()
-    f.write(bytes([len(s)]))
-    f.write(s)
+    return bytes([len(s)]) + s
+
+def write_metadata(f, pairs):
+    data = b''.join(encode_string(k) + encode_string(v) for k, v in pairs)
+    f.write(pack("<I", len(data)))
+    f.write(data)
+    return 4 + len(data)
 
-def read_header(f) -> (int, int):
-    (magic, version, block_size, file_count) = unpack("<8sBBxxI", f.read(16))
+def read_header(f) -> (int, int, int):
+    (magic, version, block_size, flags, file_count) = unpack("<8sBBBxI", f.read(16))
     if magic != MAGIC:
         raise BadMagicException()
     if version != VERSION:
         raise UnsupportedVersionException()
-    return (block_size, file_count)
+    return (block_size, file_count, flags)
 
 def read_entry(f) -> (int, int, int):
     return unpack("<III", f.read(12))
@@ -37,6 +48,17 @@ def read_string(f) -> str:
     l = f.read(1)[0]
     return f.read(l).decode('utf-8')
 
+def read_metadata(f, file_count, flags) -> [(str, str)]:
+    if flags & FLAG_METADATA == 0:
+        return []
+    f.seek(16 + 12 * file_count)
+    (l,) = unpack("<I", f.read(4))
+    end = f.tell() + l
+    pairs = []
+    while f.tell() < end:
+        pairs.append((read_string(f), read_string(f)))
+    return pairs
+
 class StringTooLargeException(Exception):
     pass
 
@@ -68,18 +90,33 @@ def create(args):
     files = sorted(map(str, files))
     file_count = len(files)
 
+    # Build host and time vary between otherwise identical builds, so only record them if
+    # asked to or if SOURCE_DATE_EPOCH pins the time.
+    metadata = [('tool', 'nrofs.py')]
+    epoch = os.environ.get('SOURCE_DATE_EPOCH')
+    if epoch is not None:
+        metadata.append(('build-time', str(int(epoch))))
+    elif not args.reproducible:
+        metadata.append(('build-time', str(int(time.time()))))
+    if not args.reproducible:
+        metadata.append(('build-host', socket.gethostname()))
+    metadata.sort()
+
     # Open file twice so we can write entries & data simultaneously without explicitly
     # seeking all the time
     ar_meta = open(args.output, 'wb')
     ar_data = open(args.output, 'wb')
 
-    write_header(ar_meta, args.block_size, file_count)
+    write_header(ar_meta, args.block_size, file_count, FLAG_METADATA)
+    ar_meta.seek(16 + 12 * file_count)
+    metadata_size = write_metadata(ar_meta, metadata)
+    ar_meta.seek(16)
 
     block_mask = (1 << args.block_size) - 1
     calc_blocks = lambda n: (n + block_mask) >> args.block_size
 
-    total_strings_size = sum(map(len, files))
-    total_meta_size = 16 + 12 * file_count + total_strings_size
+    total_strings_size = sum(len(encode_string(f)) for f in files)
+    total_meta_size = 16 + 12 * file_count + metadata_size + total_strings_size
     total_meta_blocks = calc_blocks(total_meta_size)
 
     # Write entries & file data
@@ -101,19 +138,22 @@ def create(args):
         next_block_addr += bs
         if s & block_mask != 0:
             ar_data.seek(next_block_addr << args.block_size)
-        next_string_addr += 1 + len(f)
+        next_string_addr += len(encode_string(f))
 
     # Write strings
+    ar_meta.seek(16 + 12 * file_count + metadata_size)
     for f in files:
         write_string(ar_meta, f)
 
 def list_files(args):
     ar_meta = open(args.output, 'rb')
     ar_data = open(args.output, 'rb')
-    block_size, file_count = read_header(ar_meta)
+    block_size, file_count, flags = read_header(ar_meta)
     if args.verbose:
         print('block size:', block_size)
         print('file count:', file_count)
+        for k, v in read_metadata(ar_data, file_count, flags):
+            print('%s: %s' % (k, v))
     for _ in range(file_count):
         filename_addr, block_addr, file_size = read_entry(ar_meta)
         ar_data.seek(filename_addr)
@@ -132,6 +172,7 @@ if __name__ == '__main__':
     p.add_argument('-v', '--verbose', action='store_true')
     p.add_argument('-b', '--block-size', type=int, default=12, help="Block size as a power of 2")
     p.add_argument('-C', '--change-dir', help='Change to the given directory before collecting files')
+    p.add_argument('--reproducible', action='store_true', help='Omit build host and time from the metadata')
     args = p.parse_args()
 
     if args.list:
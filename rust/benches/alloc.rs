@@ -0,0 +1,53 @@
+//! Allocations held by structures built from an image.
+//!
+//! Run with `cargo bench --features std --bench alloc` from the `rust` directory.
+//! This is separate from the timings as counting every allocation slows them down.
+
+use nrofs::{reader_io, BlockSize, Header, Tree, Writer};
+use std::{
+	alloc::{GlobalAlloc, Layout, System},
+	io::Cursor,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts live allocations to show how many a structure holds on to.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		LIVE.fetch_add(1, Ordering::Relaxed);
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		LIVE.fetch_sub(1, Ordering::Relaxed);
+		System.dealloc(ptr, layout)
+	}
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+fn main() {
+	for files in [1_000, 10_000, 100_000] {
+		// Empty files spread over directories of 64 entries, as in the read benchmark.
+		let mut w = Writer::new(BlockSize::new(12).unwrap());
+		for i in 0..files {
+			w.add(format!("d{}/f{}", i / 64, i % 64).as_bytes(), &[])
+				.unwrap();
+		}
+		let mut img = Cursor::new(w.finish().unwrap());
+		let h = Header::from_reader(&mut img).unwrap();
+		let live = LIVE.load(Ordering::Relaxed);
+		let tree = Tree::new(&h, usize::MAX, reader_io(&mut img)).unwrap();
+		let n = LIVE.load(Ordering::Relaxed) - live;
+		println!(
+			"{:<40} {:>12}",
+			format!("allocations held by tree of {} files", files),
+			n
+		);
+		drop(tree);
+	}
+}
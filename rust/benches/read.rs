@@ -0,0 +1,96 @@
+//! Timings of common read paths on synthetic in-memory images.
+//!
+//! Run with `cargo bench --features std` from the `rust` directory.
+//! Images are kept in memory so the results don't depend on the disk or page cache.
+
+use nrofs::{reader_io, BlockSize, Header, Tree, Writer};
+use std::{
+	hint::black_box,
+	io::{Cursor, Read, Seek, SeekFrom},
+	time::{Duration, Instant},
+};
+
+/// Build an image with `files` files of `size` bytes spread over directories of 64 entries.
+fn image(files: u32, size: usize) -> Cursor<Vec<u8>> {
+	let mut w = Writer::new(BlockSize::new(12).unwrap());
+	let data = vec![0xa5; size];
+	for i in 0..files {
+		w.add(format!("d{}/f{}", i / 64, i % 64).as_bytes(), &data)
+			.unwrap();
+	}
+	Cursor::new(w.finish().unwrap())
+}
+
+/// Run `f` repeatedly for about a second and print the average time per iteration.
+fn bench(name: &str, mut f: impl FnMut()) -> Duration {
+	let start = Instant::now();
+	let mut n = 0;
+	while start.elapsed() < Duration::from_secs(1) {
+		f();
+		n += 1;
+	}
+	let t = start.elapsed() / n;
+	println!("{:<40} {:>12?}", name, t);
+	t
+}
+
+fn main() {
+	for files in [1_000, 10_000, 100_000] {
+		let mut img = image(files, 16);
+		bench(&format!("mount {} files", files), || {
+			let h = Header::from_reader(&mut img).unwrap();
+			black_box(Tree::new(&h, usize::MAX, reader_io(&mut img)).unwrap());
+		});
+	}
+
+	let mut img = image(10_000, 4096);
+	let h = Header::from_reader(&mut img).unwrap();
+	let mut buf = vec![0; 4096];
+	let mut x = 1u32;
+	bench("random read 4 KiB", || {
+		// xorshift, which is good enough to defeat any locality.
+		x ^= x << 13;
+		x ^= x >> 17;
+		x ^= x << 5;
+		let e = h
+			.get(x % h.file_count(), reader_io(&mut img))
+			.unwrap()
+			.unwrap();
+		img.seek(SeekFrom::Start(e.offset(&h))).unwrap();
+		img.read_exact(&mut buf[..e.size() as usize]).unwrap();
+		black_box(&buf);
+	});
+
+	let mut img = image(16, 1 << 24);
+	let h = Header::from_reader(&mut img).unwrap();
+	let mut buf = vec![0; 1 << 16];
+	let t = bench("sequential read 256 MiB", || {
+		for i in 0..h.file_count() {
+			let e = h.get(i, reader_io(&mut img)).unwrap().unwrap();
+			img.seek(SeekFrom::Start(e.offset(&h))).unwrap();
+			for _ in 0..e.size() as usize / buf.len() {
+				img.read_exact(&mut buf).unwrap();
+				black_box(&buf);
+			}
+		}
+	});
+	println!("{:<40} {:>9.1} MiB/s", "", 256.0 / t.as_secs_f64());
+
+	let mut img = image(100_000, 0);
+	let h = Header::from_reader(&mut img).unwrap();
+	let tree = Tree::new(&h, usize::MAX, reader_io(&mut img)).unwrap();
+	bench("list all directories", || {
+		for d in 0..tree.dir_count() as u32 {
+			for c in tree.children(d) {
+				black_box(c);
+			}
+		}
+	});
+	bench("look up every child", || {
+		for d in 0..tree.dir_count() as u32 {
+			for (name, _) in tree.children(d) {
+				black_box(tree.child(d, name));
+			}
+		}
+	});
+}
@@ -0,0 +1,84 @@
+use crate::{Entry, Header, Op};
+
+/// Keeps the last `N` entries read through it so repeated lookups need no I/O.
+///
+/// The oldest entry is replaced first.
+#[derive(Clone, Debug)]
+pub struct Cache<const N: usize> {
+	header: Header,
+	slots: [Option<(u32, Entry)>; N],
+	next: usize,
+}
+
+impl<const N: usize> Cache<N> {
+	pub fn new(header: Header) -> Self {
+		Self { header, slots: [None; N], next: 0 }
+	}
+
+	pub fn header(&self) -> &Header {
+		&self.header
+	}
+
+	/// Get an entry from the cache or read it with [`Header::get`].
+	pub fn get<R, Io>(&mut self, index: u32, io: Io) -> Option<Result<Entry, R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		if let Some((_, e)) = self.slots.iter().flatten().find(|(i, _)| *i == index) {
+			return Some(Ok(*e));
+		}
+		let e = self.header.get(index, io)?;
+		if let (Ok(e), Some(slot)) = (&e, self.slots.get_mut(self.next)) {
+			*slot = Some((index, *e));
+			self.next = (self.next + 1) % N;
+		}
+		Some(e)
+	}
+
+	/// Iterate over all entries in order, going through the cache.
+	pub fn iter<'a, R, Io>(&'a mut self, mut io: Io) -> impl Iterator<Item = Result<Entry, R>> + 'a
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R> + 'a,
+	{
+		(0..self.header.file_count()).map(move |i| self.get(i, &mut io).unwrap())
+	}
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+	use super::*;
+	use crate::tests::{image, load, slice_io};
+	use core::cell::Cell;
+
+	#[test]
+	fn get() {
+		let img = image(&[("a", b"1"), ("b", b"2"), ("c", b"3")]);
+		let reads = &Cell::new(0);
+		let io = || {
+			let mut io = slice_io(&img);
+			move |op: Op<'_>| {
+				reads.set(reads.get() + usize::from(matches!(op, Op::Read(_))));
+				io(op)
+			}
+		};
+		let mut c = Cache::<2>::new(load(&img));
+		let e = c.get(0, io()).unwrap().unwrap();
+		assert_eq!(c.get(0, io()).unwrap(), Ok(e));
+		assert_eq!(reads.get(), 1);
+		c.get(1, io());
+		c.get(1, io());
+		assert_eq!(reads.get(), 2);
+		// A third entry replaces the oldest.
+		c.get(2, io());
+		c.get(1, io());
+		assert_eq!(reads.get(), 3);
+		assert_eq!(c.get(0, io()).unwrap(), Ok(e));
+		assert_eq!(reads.get(), 4);
+		assert!(c.get(3, io()).is_none());
+		// Without slots every get reads.
+		let mut c = Cache::<0>::new(load(&img));
+		c.get(0, io());
+		c.get(0, io());
+		assert_eq!(reads.get(), 6);
+	}
+}
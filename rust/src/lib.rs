@@ -1,48 +1,223 @@
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+mod tree;
+#[cfg(feature = "alloc")]
+mod writer;
+
+mod cache;
+#[cfg(feature = "std")]
+mod reader;
+
+pub use cache::Cache;
+#[cfg(feature = "std")]
+pub use reader::reader_io;
+#[cfg(feature = "alloc")]
+pub use tree::{components, Node, Tree, TreeError};
+#[cfg(feature = "alloc")]
+pub use writer::{
+	recommend_block_size, DataWriter, FromExistingError, StreamError, StreamWriter, WriteError,
+	Writer, WriterBuilder, BLOCK_COST,
+};
+
+/// Emit a trace event if the `log` feature is enabled.
+macro_rules! trace {
+	($($arg:tt)*) => {
+		#[cfg(feature = "log")]
+		log::trace!($($arg)*);
+	};
+}
+
+use core::{
+	cmp::Ordering,
+	fmt, iter,
+	ops::{Range, RangeInclusive},
+};
+
 const MAGIC: [u8; 8] = *b"NrRdOnly";
 const VERSION: u8 = 0;
 
-fn parse_header<R>(d: [u8; 16]) -> Result<(u8, u32), ParseHeaderError<R>> {
-	if &d[..8] != &MAGIC {
+/// The newest format version this crate can read.
+pub const LATEST_VERSION: u8 = VERSION;
+
+const FLAG_METADATA: u8 = 1 << 0;
+const FLAG_PATH_INDEX: u8 = 1 << 1;
+const FLAGS_KNOWN: u8 = FLAG_METADATA | FLAG_PATH_INDEX;
+
+/// 32-bit FNV-1a hash of a name, as used by the path index.
+fn hash_name(name: &[u8]) -> u32 {
+	name.iter().fold(0x811c_9dc5, |h, &c| {
+		(h ^ u32::from(c)).wrapping_mul(0x0100_0193)
+	})
+}
+
+fn parse_header<R>(
+	d: [u8; 16],
+	supported: RangeInclusive<u8>,
+) -> Result<Header, ParseHeaderError<R>> {
+	if d[..8] != MAGIC {
 		Err(ParseHeaderError::BadMagic)
-	} else if d[8] != VERSION {
-		Err(ParseHeaderError::UnsupportedVersion)
+	} else if !supported.contains(&d[8]) {
+		Err(ParseHeaderError::UnsupportedVersion { found: d[8], supported })
 	} else {
-		Ok((d[9], u32::from_le_bytes(d[12..].try_into().unwrap())))
+		parse_fields(d)
+	}
+}
+
+/// Parse the header without checking the magic or version.
+fn parse_fields<R>(d: [u8; 16]) -> Result<Header, ParseHeaderError<R>> {
+	let block_size = BlockSize::new(d[9]).ok_or(ParseHeaderError::InvalidBlockSize)?;
+	if d[10] & !FLAGS_KNOWN != 0 {
+		return Err(ParseHeaderError::UnknownFlags(d[10]));
 	}
+	let file_count = u32::from_le_bytes(d[12..].try_into().unwrap());
+	trace!(
+		"header: block size {}, flags {:#x}, {} files",
+		block_size.as_bytes(),
+		d[10],
+		file_count
+	);
+	Ok(Header { block_size, flags: d[10], file_count, first: None })
 }
 
 fn parse_entry(d: [u8; 12]) -> Entry {
 	let f = |d: &[_]| u32::from_le_bytes(d.try_into().unwrap());
 	let (filename_addr, block_addr, file_size) = (f(&d[..4]), f(&d[4..8]), f(&d[8..]));
+	trace!(
+		"entry: name at {}, block {}, size {}",
+		filename_addr,
+		block_addr,
+		file_size
+	);
 	Entry { filename_addr, block_addr, file_size }
 }
 
+/// The length of the image, or `u64::MAX` if `io` doesn't know it.
+fn image_len<R>(mut io: impl FnMut(Op<'_>) -> Result<(), R>) -> Result<u64, R> {
+	let mut len = 0;
+	io(Op::Len(&mut len))?;
+	// An image with entries is never empty.
+	Ok(if len == 0 { u64::MAX } else { len })
+}
+
+/// Read a string prefixed with its length, or only the length if it doesn't fit in `buf`.
+///
+/// A buffer of 255 bytes always fits.
 fn read_string<R>(
-	buf: &mut [u8; 255],
+	buf: &mut [u8],
 	mut f: impl FnMut(&mut [u8]) -> Result<(), R>,
-) -> Result<&[u8], R> {
+) -> Result<Result<&[u8], u8>, R> {
 	let mut l = [0];
 	f(&mut l)?;
-	let buf = &mut buf[..l[0].into()];
-	f(buf).map(|()| &*buf)
+	match buf.get_mut(..l[0].into()) {
+		Some(buf) => f(buf).map(|()| Ok(&*buf)),
+		None => Ok(Err(l[0])),
+	}
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ParseHeaderError<R> {
 	BadMagic,
-	UnsupportedVersion,
+	/// The version of the image is not one of the versions accepted by the caller.
+	UnsupportedVersion {
+		found: u8,
+		supported: RangeInclusive<u8>,
+	},
+	InvalidBlockSize,
+	/// Flags this version of the crate doesn't know are set. Holds all flags.
+	UnknownFlags(u8),
+	/// The image ends before the header or the entry table.
+	Truncated,
 	Other(R),
 }
 
+impl<R: fmt::Display> fmt::Display for ParseHeaderError<R> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::BadMagic => f.write_str("bad magic"),
+			Self::UnsupportedVersion { found, supported } => write!(
+				f,
+				"unsupported version {}, expected {} to {}",
+				found,
+				supported.start(),
+				supported.end()
+			),
+			Self::InvalidBlockSize => f.write_str("invalid block size"),
+			Self::UnknownFlags(b) => write!(f, "unknown flags {:#04x}", b),
+			Self::Truncated => f.write_str("image too short for header or entry table"),
+			Self::Other(e) => e.fmt(f),
+		}
+	}
+}
+
+impl<R: core::error::Error + 'static> core::error::Error for ParseHeaderError<R> {
+	fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+		match self {
+			Self::Other(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
 #[derive(Debug)]
+#[non_exhaustive]
+pub enum EntryError<R> {
+	/// The entry points outside of the image.
+	Corrupt,
+	/// The name doesn't fit in the buffer. Holds the length of the name.
+	NameTooLong(u8),
+	Other(R),
+}
+
+impl<R: fmt::Display> fmt::Display for EntryError<R> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Corrupt => f.write_str("entry points outside of the image"),
+			Self::NameTooLong(n) => write!(f, "name of {} bytes doesn't fit in buffer", n),
+			Self::Other(e) => e.fmt(f),
+		}
+	}
+}
+
+impl<R: core::error::Error + 'static> core::error::Error for EntryError<R> {
+	fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+		match self {
+			Self::Other(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+/// Problems ignored by [`Header::load_lenient`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Warnings {
+	pub bad_magic: bool,
+	pub unsupported_version: bool,
+}
+
+/// The size of a block as a power of 2.
+///
+/// Block addresses are 32 bits, so the shift is less than 32 to keep byte offsets within a
+/// `u64`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockSize(u8);
+
+/// The header is a few bytes and doesn't borrow the image, so it is cheap to copy.
+#[derive(Clone, Copy, Debug)]
 pub struct Header {
-	block_size: u8,
+	block_size: BlockSize,
+	flags: u8,
 	file_count: u32,
+	first: Option<Entry>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Entry {
 	filename_addr: u32,
 	block_addr: u32,
@@ -53,39 +228,518 @@ pub enum Op<'a> {
 	Seek(u64),
 	Advance(i64),
 	Read(&'a mut [u8]),
+	/// Store the length of the image in bytes.
+	///
+	/// Leaving it at 0 means the length is unknown, in which case checks against it pass.
+	Len(&'a mut u64),
 }
 
 impl Header {
-	pub fn load<R, Io>(mut io: Io) -> Result<Self, ParseHeaderError<R>>
+	/// Load the header of an image of any version up to [`LATEST_VERSION`].
+	pub fn load<R, Io>(io: Io) -> Result<Self, ParseHeaderError<R>>
+	where
+		Io: FnMut(&mut [u8]) -> Result<(), R>,
+	{
+		Self::load_versions(io, 0..=LATEST_VERSION)
+	}
+
+	/// Load the header only if the version of the image is in `supported`.
+	///
+	/// Versions newer than [`LATEST_VERSION`] are still rejected, as they can't be parsed.
+	pub fn load_versions<R, Io>(
+		mut io: Io,
+		supported: RangeInclusive<u8>,
+	) -> Result<Self, ParseHeaderError<R>>
 	where
 		Io: FnMut(&mut [u8]) -> Result<(), R>,
 	{
+		let (start, end) = supported.into_inner();
+		let supported = start..=if end > LATEST_VERSION {
+			LATEST_VERSION
+		} else {
+			end
+		};
 		let mut b = [0; 16];
 		io(&mut b)
 			.map_err(ParseHeaderError::Other)
-			.and_then(|()| parse_header(b))
-			.map(|(block_size, file_count)| Self { block_size, file_count })
+			.and_then(|()| parse_header(b, supported))
+	}
+
+	/// Load the header even if the magic or version is wrong, for recovering damaged images.
+	///
+	/// The block size and flags must still be valid. Any other problems are returned as warnings.
+	pub fn load_lenient<R, Io>(mut io: Io) -> Result<(Self, Warnings), ParseHeaderError<R>>
+	where
+		Io: FnMut(&mut [u8]) -> Result<(), R>,
+	{
+		let mut b = [0; 16];
+		io(&mut b).map_err(ParseHeaderError::Other)?;
+		let w = Warnings { bad_magic: b[..8] != MAGIC, unsupported_version: b[8] != VERSION };
+		parse_fields(b).map(|h| (h, w))
+	}
+
+	/// Load the header together with the first entry, which directly follows it, in one read.
+	///
+	/// The first entry is kept so `get(0)` and the start of `iter` need no I/O.
+	/// An image without entries may be too short for the read, in which case only the header
+	/// is read again.
+	pub fn load_prefetch<R, Io>(mut io: Io) -> Result<Self, ParseHeaderError<R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut b = [0; 28];
+		io(Op::Seek(0)).map_err(ParseHeaderError::Other)?;
+		match io(Op::Read(&mut b)) {
+			Ok(()) => {
+				let mut h = parse_header(b[..16].try_into().unwrap(), 0..=LATEST_VERSION)?;
+				if h.file_count > 0 {
+					h.first = Some(parse_entry(b[16..].try_into().unwrap()));
+				}
+				Ok(h)
+			}
+			Err(e) => {
+				io(Op::Seek(0)).map_err(ParseHeaderError::Other)?;
+				let h = Self::load(|b| io(Op::Read(b)))?;
+				(h.file_count == 0)
+					.then_some(h)
+					.ok_or(ParseHeaderError::Other(e))
+			}
+		}
+	}
+
+	/// Load the header and check that an image of `len` bytes can hold the entry table.
+	pub fn load_with_len<R, Io>(io: Io, len: u64) -> Result<Self, ParseHeaderError<R>>
+	where
+		Io: FnMut(&mut [u8]) -> Result<(), R>,
+	{
+		Self::load(io).and_then(|h| {
+			(h.data_region_start() <= len)
+				.then_some(h)
+				.ok_or(ParseHeaderError::Truncated)
+		})
 	}
 
 	pub fn get<R, Io>(&self, index: u32, io: Io) -> Option<Result<Entry, R>>
 	where
 		Io: FnMut(Op<'_>) -> Result<(), R>,
 	{
-		(index < self.file_count).then(|| get(index, io))
+		(index < self.file_count).then(|| match &self.first {
+			Some(e) if index == 0 => Ok(*e),
+			_ => get(index, io),
+		})
+	}
+
+	/// Get an entry and check that its name and data lie within the image.
+	pub fn get_checked<R, Io>(&self, index: u32, mut io: Io) -> Option<Result<Entry, EntryError<R>>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		self.get(index, &mut io).map(|e| {
+			let e = e.map_err(EntryError::Other)?;
+			let image_len = image_len(&mut io).map_err(EntryError::Other)?;
+			let ok = u64::from(e.filename_addr) < image_len && e.data_range(self).end <= image_len;
+			ok.then_some(e).ok_or(EntryError::Corrupt)
+		})
+	}
+
+	/// Read the last entry in the table without iterating.
+	pub fn last_entry<R, Io>(&self, io: Io) -> Option<Result<Entry, R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		self.file_count.checked_sub(1).and_then(|i| self.get(i, io))
+	}
+
+	pub fn nth_name<'a, R, Io>(
+		&self,
+		index: u32,
+		buf: &'a mut [u8; 255],
+		mut io: Io,
+	) -> Option<Result<&'a [u8], R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		self.get(index, &mut io)
+			.map(|e| e.and_then(|e| e.name(buf, io)))
+	}
+
+	/// Pass the name of every entry to `f` in table order.
+	///
+	/// A single buffer is reused for all names, so nothing is allocated.
+	pub fn for_each_name<R, Io, F>(&self, mut io: Io, mut f: F) -> Result<(), R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+		F: FnMut(&[u8]) -> Result<(), R>,
+	{
+		let mut buf = [0; 255];
+		for i in 0..self.file_count {
+			let e = self.get(i, &mut io).unwrap()?;
+			f(e.name(&mut buf, &mut io)?)?;
+		}
+		Ok(())
 	}
 
+	/// Iterate over all entries in order.
+	///
+	/// Every entry is sought to before it is read, so `io` may be used for other reads in
+	/// between calls to `next`.
 	pub fn iter<R, Io>(&self, io: Io) -> Iter<R, Io>
 	where
 		Io: FnMut(Op<'_>) -> Result<(), R>,
 	{
-		Iter { io, count: self.file_count, offset: 0 }
+		Iter {
+			io,
+			count: self.file_count,
+			offset: 0,
+			first: self.first,
+			stop_on_error: false,
+		}
+	}
+
+	/// Scan all entries in order, with the caller performing each read.
+	pub fn scanner(&self) -> EntryScanner {
+		EntryScanner {
+			offset: 0,
+			count: self.file_count,
+			first: self.first,
+			buf: [0; 12],
+			pending: false,
+		}
+	}
+
+	/// Iterate over the entries for which `pred` returns `true`.
+	///
+	/// Errors are always yielded.
+	pub fn iter_filter<R, Io, P>(&self, io: Io, pred: P) -> impl Iterator<Item = Result<Entry, R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+		P: Fn(&Entry) -> bool,
+	{
+		self.iter(io)
+			.filter(move |e| e.as_ref().map_or(true, &pred))
+	}
+
+	/// Iterate over the index, offset and size of the data of every file in table order.
+	///
+	/// Only the entry table is read.
+	pub fn layout<R, Io>(&self, io: Io) -> impl Iterator<Item = Result<(u32, u64, u32), R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let h = *self;
+		(0..).zip(self.iter(io)).map(move |(i, e)| {
+			e.map(|e| {
+				let (offset, size) = e.data_extent(&h);
+				(i, offset, size)
+			})
+		})
+	}
+
+	/// Find the range of entries inside the directory `dir_prefix` in a name-sorted table.
+	///
+	/// The range starts at the first entry whose name is `dir_prefix` followed by a `/` and
+	/// contains every entry in that directory, including those in subdirectories.
+	/// A direct child is an entry in the range with no further `/` after the prefix.
+	/// Descendants of subdirectories sort in between direct children and can't be excluded
+	/// from a contiguous range, so use [`Header::read_dir`] to list only the direct children.
+	///
+	/// Trailing slashes in `dir_prefix` are ignored. An empty prefix is the root and yields
+	/// the entire table.
+	pub fn find_range<R, Io>(&self, dir_prefix: &[u8], mut io: Io) -> Result<Range<u32>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut prefix = dir_prefix;
+		while let [p @ .., b'/'] = prefix {
+			prefix = p;
+		}
+		if prefix.is_empty() {
+			return Ok(0..self.file_count);
+		}
+		// '0' comes right after '/', so this skips past every name starting with "prefix/".
+		let start = self.partition_point(|n| cmp_prefix(n, prefix, b'/').is_lt(), &mut io)?;
+		let end = self.partition_point(|n| cmp_prefix(n, prefix, b'0').is_lt(), &mut io)?;
+		Ok(start..end)
+	}
+
+	/// List the direct children of the directory `dir` in a name-sorted table without
+	/// allocating.
+	///
+	/// Subdirectories are yielded once with the range of entries inside them, which is
+	/// skipped over. Trailing slashes in `dir` are ignored and an empty `dir` is the root.
+	/// A directory that doesn't exist has no children. Unlike in a `Tree` a file with the
+	/// same name as a directory is not shadowed by it.
+	pub fn read_dir<R, Io>(&self, dir: &[u8], mut io: Io) -> Result<ReadDir<R, Io>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let range = self.find_range(dir, &mut io)?;
+		let mut prefix = dir;
+		while let [p @ .., b'/'] = prefix {
+			prefix = p;
+		}
+		let prefix_len = if prefix.is_empty() {
+			0
+		} else {
+			prefix.len() + 1
+		};
+		Ok(ReadDir { header: *self, io, prefix_len, index: range.start, end: range.end })
+	}
+
+	fn partition_point<R, Io>(
+		&self,
+		mut pred: impl FnMut(&[u8]) -> bool,
+		mut io: Io,
+	) -> Result<u32, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut buf = [0; 255];
+		let (mut low, mut high) = (0, self.file_count);
+		while low < high {
+			let mid = low + (high - low) / 2;
+			if pred(get(mid, &mut io)?.name(&mut buf, &mut io)?) {
+				low = mid + 1;
+			} else {
+				high = mid;
+			}
+		}
+		Ok(low)
+	}
+
+	/// Find the index of the entry whose data contains the byte `offset` of the image.
+	///
+	/// The table must be sorted by data offset, which is the case for images where data is laid
+	/// out in table order, such as those created by `Writer`.
+	pub fn entry_at_offset<R, Io>(&self, offset: u64, mut io: Io) -> Result<Option<u32>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let (mut low, mut high) = (0, self.file_count);
+		while low < high {
+			let mid = low + (high - low) / 2;
+			if get(mid, &mut io)?.offset(self) <= offset {
+				low = mid + 1;
+			} else {
+				high = mid;
+			}
+		}
+		match low.checked_sub(1) {
+			Some(i) => Ok(get(i, &mut io)?
+				.data_range(self)
+				.contains(&offset)
+				.then_some(i)),
+			None => Ok(None),
+		}
+	}
+
+	/// Read the key/value pairs of the metadata section.
+	///
+	/// Images without a metadata section yield no pairs.
+	pub fn metadata<R, Io>(&self, mut io: Io) -> Result<Metadata<R, Io>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let offset = self.data_region_start();
+		let mut len = [0; 4];
+		if self.flags & FLAG_METADATA != 0 {
+			io(Op::Seek(offset))?;
+			io(Op::Read(&mut len))?;
+		}
+		let end = offset + 4 + u64::from(u32::from_le_bytes(len));
+		Ok(Metadata { io, offset: offset + 4, end })
+	}
+
+	/// The end of the data of the file that ends last.
+	///
+	/// Anything after this in the image is unused. Images without files end after the metadata
+	/// section and path index, or at the entry table if they have neither.
+	pub fn data_region_end<R, Io>(&self, mut io: Io) -> Result<u64, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let start = self.sections_end(&mut io)?;
+		self.iter(io)
+			.try_fold(start, |end, e| e.map(|e| end.max(e.data_range(self).end)))
+	}
+
+	/// The offset of the path index, which follows the metadata section if there is one.
+	fn path_index_offset<R, Io>(&self, mut io: Io) -> Result<u64, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut offset = self.data_region_start();
+		if self.flags & FLAG_METADATA != 0 {
+			let mut len = [0; 4];
+			io(Op::Seek(offset))?;
+			io(Op::Read(&mut len))?;
+			offset += 4 + u64::from(u32::from_le_bytes(len));
+		}
+		Ok(offset)
+	}
+
+	/// The end of the metadata section and path index.
+	fn sections_end<R, Io>(&self, mut io: Io) -> Result<u64, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let offset = self.path_index_offset(&mut io)?;
+		if self.flags & FLAG_PATH_INDEX == 0 {
+			return Ok(offset);
+		}
+		let mut count = [0; 4];
+		io(Op::Seek(offset))?;
+		io(Op::Read(&mut count))?;
+		Ok(offset + 4 + 8 * u64::from(u32::from_le_bytes(count)))
+	}
+
+	/// Find the index of the entry named `path`.
+	///
+	/// The path index is used if the image has one. Otherwise the table must be sorted by name.
+	pub fn resolve_path<R, Io>(&self, path: &[u8], mut io: Io) -> Result<Option<u32>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut buf = [0; 255];
+		let mut is = |i: u32, io: &mut Io| -> Result<bool, R> {
+			Ok(get(i, &mut *io)?.name(&mut buf, &mut *io)? == path)
+		};
+		if self.flags & FLAG_PATH_INDEX == 0 {
+			let i = self.partition_point(|n| n < path, &mut io)?;
+			return Ok((i < self.file_count && is(i, &mut io)?).then_some(i));
+		}
+
+		let offset = self.path_index_offset(&mut io)?;
+		let mut b = [0; 4];
+		io(Op::Seek(offset))?;
+		io(Op::Read(&mut b))?;
+		let count = u32::from_le_bytes(b);
+		let pair = |k: u32, io: &mut Io| -> Result<(u32, u32), R> {
+			let mut b = [0; 8];
+			io(Op::Seek(offset + 4 + u64::from(k) * 8))?;
+			io(Op::Read(&mut b))?;
+			let f = |d: &[u8]| u32::from_le_bytes(d.try_into().unwrap());
+			Ok((f(&b[..4]), f(&b[4..])))
+		};
+
+		let hash = hash_name(path);
+		let (mut low, mut high) = (0, count);
+		while low < high {
+			let mid = low + (high - low) / 2;
+			if pair(mid, &mut io)?.0 < hash {
+				low = mid + 1;
+			} else {
+				high = mid;
+			}
+		}
+		for k in low..count {
+			let (h, i) = pair(k, &mut io)?;
+			if h != hash {
+				break;
+			}
+			if i < self.file_count && is(i, &mut io)? {
+				return Ok(Some(i));
+			}
+		}
+		Ok(None)
+	}
+
+	/// Find up to `max` names starting with `prefix` in a name-sorted table.
+	///
+	/// Unlike [`Header::find_range`] the prefix may end in the middle of a path component.
+	#[cfg(feature = "alloc")]
+	pub fn complete_prefix<R, Io>(
+		&self,
+		prefix: &[u8],
+		max: usize,
+		mut io: Io,
+	) -> Result<alloc::vec::Vec<alloc::vec::Vec<u8>>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut v = alloc::vec::Vec::new();
+		let mut buf = [0; 255];
+		let start = self.partition_point(|n| n < prefix, &mut io)?;
+		for i in start..self.file_count {
+			if v.len() >= max {
+				break;
+			}
+			let name = get(i, &mut io)?.name(&mut buf, &mut io)?;
+			if !name.starts_with(prefix) {
+				break;
+			}
+			v.push(name.into());
+		}
+		Ok(v)
+	}
+
+	/// Find the indices of all entries whose data overlaps the byte range `range` of the image.
+	///
+	/// Empty files never overlap anything.
+	#[cfg(feature = "alloc")]
+	pub fn entries_in_range<R, Io>(
+		&self,
+		range: Range<u64>,
+		io: Io,
+	) -> Result<alloc::vec::Vec<u32>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut v = alloc::vec::Vec::new();
+		for (i, e) in (0..).zip(self.iter(io)) {
+			let r = e?.data_range(self);
+			if !r.is_empty() && r.start < range.end && range.start < r.end {
+				v.push(i);
+			}
+		}
+		Ok(v)
+	}
+
+	/// Read all entries with a few large reads of the table.
+	///
+	/// Unlike [`Header::iter`] this doesn't need a separate read for each entry. The table is
+	/// read in chunks of up to 4096 entries, so a file count the image doesn't have room for
+	/// fails at a read instead of allocating memory for all of them up front.
+	#[cfg(feature = "alloc")]
+	pub fn load_all_entries<R, Io>(&self, mut io: Io) -> Result<alloc::vec::Vec<Entry>, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		const CHUNK: u32 = 1 << 12;
+		let mut buf = alloc::vec![0; self.file_count.min(CHUNK) as usize * 12];
+		let mut entries = alloc::vec::Vec::new();
+		io(Op::Seek(16))?;
+		let mut left = self.file_count;
+		while left > 0 {
+			let n = left.min(CHUNK);
+			let b = &mut buf[..n as usize * 12];
+			io(Op::Read(b))?;
+			let e = b
+				.chunks_exact(12)
+				.map(|b| parse_entry(b.try_into().unwrap()));
+			entries.extend(e);
+			left -= n;
+		}
+		Ok(entries)
+	}
+
+	/// The end of the header and entry table.
+	///
+	/// This is the lowest offset file data can start at.
+	pub fn data_region_start(&self) -> u64 {
+		16 + u64::from(self.file_count) * 12
 	}
 
 	pub fn file_count(&self) -> u32 {
 		self.file_count
 	}
 
-	pub fn block_size(&self) -> u8 {
+	/// Whether the image has an index for [`Header::resolve_path`].
+	pub fn has_path_index(&self) -> bool {
+		self.flags & FLAG_PATH_INDEX != 0
+	}
+
+	pub fn block_size(&self) -> BlockSize {
 		self.block_size
 	}
 }
@@ -96,20 +750,230 @@ impl Entry {
 		Io: FnMut(Op<'_>) -> Result<(), R>,
 	{
 		io(Op::Seek(self.filename_addr.into()))?;
+		let name = read_string(buf, |b| io(Op::Read(b)))?.unwrap();
+		trace!("name at {}: {:?}", self.filename_addr, name);
+		Ok(name)
+	}
+
+	/// Read the name into a buffer of any size.
+	///
+	/// Returns [`EntryError::NameTooLong`] if the name doesn't fit, in which case only its
+	/// length has been read. A buffer of 255 bytes fits every name.
+	pub fn name_into<'a, R, Io>(
+		&self,
+		buf: &'a mut [u8],
+		mut io: Io,
+	) -> Result<&'a [u8], EntryError<R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		io(Op::Seek(self.filename_addr.into())).map_err(EntryError::Other)?;
 		read_string(buf, |b| io(Op::Read(b)))
+			.map_err(EntryError::Other)?
+			.map_err(EntryError::NameTooLong)
+	}
+
+	/// Read the name after checking that it lies within the image.
+	pub fn name_checked<'a, R, Io>(
+		&self,
+		buf: &'a mut [u8; 255],
+		mut io: Io,
+	) -> Result<&'a [u8], EntryError<R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let image_len = image_len(&mut io).map_err(EntryError::Other)?;
+		if u64::from(self.filename_addr) >= image_len {
+			return Err(EntryError::Corrupt);
+		}
+		let len = self.name_len(&mut io).map_err(EntryError::Other)?;
+		if u64::from(self.filename_addr) + 1 + u64::from(len) > image_len {
+			return Err(EntryError::Corrupt);
+		}
+		let buf = &mut buf[..len.into()];
+		io(Op::Read(buf)).map_err(EntryError::Other)?;
+		Ok(buf)
+	}
+
+	/// Read the name in chunks and pass them to `out`.
+	pub fn write_name<R, Io, W>(&self, mut io: Io, mut out: W) -> Result<(), R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+		W: FnMut(&[u8]) -> Result<(), R>,
+	{
+		let mut left = usize::from(self.name_len(&mut io)?);
+		let mut buf = [0; 64];
+		while left > 0 {
+			let b = &mut buf[..left.min(64)];
+			io(Op::Read(b))?;
+			out(b)?;
+			left -= b.len();
+		}
+		Ok(())
+	}
+
+	/// Get the name from an image that is entirely in memory, such as a mapped file.
+	///
+	/// Returns `None` if the name lies outside of `image`.
+	pub fn name_from_slice<'a>(&self, image: &'a [u8]) -> Option<&'a [u8]> {
+		let start = usize::try_from(self.filename_addr).ok()?;
+		let len = usize::from(*image.get(start)?);
+		image.get(start + 1..start + 1 + len)
+	}
+
+	/// Read only the length of the name.
+	pub fn name_len<R, Io>(&self, mut io: Io) -> Result<u8, R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut l = [0];
+		io(Op::Seek(self.filename_addr.into()))?;
+		io(Op::Read(&mut l)).map(|()| l[0])
 	}
 
 	pub fn block(&self) -> u32 {
 		self.block_addr
 	}
 
+	/// The offset of the file's data in the image.
+	///
+	/// The image stores a block number rather than a byte offset, so this is always a multiple
+	/// of the block size.
 	pub fn offset(&self, header: &Header) -> u64 {
-		u64::from(self.block_addr) << header.block_size
+		u64::from(self.block_addr) << header.block_size.as_shift()
 	}
 
 	pub fn size(&self) -> u32 {
 		self.file_size
 	}
+
+	/// The record of this entry as stored in the entry table.
+	pub fn to_le_bytes(&self) -> [u8; 12] {
+		let mut d = [0; 12];
+		d[..4].copy_from_slice(&self.filename_addr.to_le_bytes());
+		d[4..8].copy_from_slice(&self.block_addr.to_le_bytes());
+		d[8..].copy_from_slice(&self.file_size.to_le_bytes());
+		d
+	}
+
+	/// The offset and size of the file's data, for use with positioned reads.
+	pub fn data_extent(&self, header: &Header) -> (u64, u32) {
+		(self.offset(header), self.file_size)
+	}
+
+	/// The byte range of the file's data in the image.
+	pub fn data_range(&self, header: &Header) -> Range<u64> {
+		let start = self.offset(header);
+		start..start + u64::from(self.file_size)
+	}
+}
+
+impl BlockSize {
+	pub const fn new(shift: u8) -> Option<Self> {
+		if shift < 32 {
+			Some(Self(shift))
+		} else {
+			None
+		}
+	}
+
+	pub fn as_shift(&self) -> u8 {
+		self.0
+	}
+
+	pub fn as_bytes(&self) -> u64 {
+		1 << self.0
+	}
+
+	/// Mask of the bits of a byte offset within a block.
+	pub fn mask(&self) -> u64 {
+		self.as_bytes() - 1
+	}
+
+	/// The amount of bytes needed after `size` bytes to reach the next block boundary.
+	pub fn padding_for(&self, size: u64) -> u64 {
+		size.wrapping_neg() & self.mask()
+	}
+}
+
+type KeyValue<'a> = (&'a [u8], &'a [u8]);
+
+pub struct Metadata<R, Io>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+{
+	io: Io,
+	offset: u64,
+	end: u64,
+}
+
+impl<R, Io> Metadata<R, Io>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+{
+	/// Read the next key/value pair.
+	pub fn next<'a>(
+		&mut self,
+		key: &'a mut [u8; 255],
+		value: &'a mut [u8; 255],
+	) -> Option<Result<KeyValue<'a>, R>> {
+		(self.offset < self.end).then(|| {
+			let io = &mut self.io;
+			io(Op::Seek(self.offset))?;
+			let k = read_string(key, |b| io(Op::Read(b)))?.unwrap();
+			let v = read_string(value, |b| io(Op::Read(b)))?.unwrap();
+			self.offset += 2 + k.len() as u64 + v.len() as u64;
+			Ok((k, v))
+		})
+	}
+}
+
+/// A direct child of a directory, as yielded by [`ReadDir`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DirChild {
+	/// A file with the index of its entry.
+	File(u32, Entry),
+	/// A subdirectory with the range of entries inside it.
+	Dir(Range<u32>),
+}
+
+pub struct ReadDir<R, Io>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+{
+	header: Header,
+	io: Io,
+	prefix_len: usize,
+	index: u32,
+	end: u32,
+}
+
+impl<R, Io> ReadDir<R, Io>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+{
+	/// Read the next child, returning its name within the directory.
+	///
+	/// An entry that can't be read is skipped, so the listing can continue after an error.
+	pub fn next<'a>(&mut self, buf: &'a mut [u8; 255]) -> Option<Result<(&'a [u8], DirChild), R>> {
+		(self.index < self.end).then(|| {
+			let i = self.index;
+			self.index = i + 1;
+			let e = get(i, &mut self.io)?;
+			let name = e.name(buf, &mut self.io)?;
+			let rest = name.get(self.prefix_len..).unwrap_or(&[]);
+			match rest.iter().position(|&c| c == b'/') {
+				Some(n) => {
+					let dir = &name[..self.prefix_len + n];
+					let r = self.header.find_range(dir, &mut self.io)?;
+					// Never go backwards, even if the table isn't sorted.
+					self.index = r.end.max(i + 1);
+					Ok((&rest[..n], DirChild::Dir(r)))
+				}
+				None => Ok((rest, DirChild::File(i, e))),
+			}
+		})
+	}
 }
 
 pub struct Iter<R, Io>
@@ -119,6 +983,26 @@ where
 	io: Io,
 	offset: u32,
 	count: u32,
+	first: Option<Entry>,
+	stop_on_error: bool,
+}
+
+impl<R, Io> Iter<R, Io>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+{
+	/// End the iteration after the first error instead of continuing with the next entry.
+	pub fn stop_on_error(mut self) -> Self {
+		self.stop_on_error = true;
+		self
+	}
+
+	fn check(&mut self, e: Result<Entry, R>) -> Result<Entry, R> {
+		if e.is_err() && self.stop_on_error {
+			self.offset = self.count;
+		}
+		e
+	}
 }
 
 impl<R, Io> Iterator for Iter<R, Io>
@@ -131,7 +1015,11 @@ where
 		(self.offset < self.count).then(|| {
 			let o = self.offset;
 			self.offset += 1;
-			get(o, &mut self.io)
+			let e = match self.first.take() {
+				Some(e) if o == 0 => Ok(e),
+				_ => get(o, &mut self.io),
+			};
+			self.check(e)
 		})
 	}
 
@@ -145,20 +1033,184 @@ where
 
 	fn nth(&mut self, n: usize) -> Option<Self::Item> {
 		let n = u32::try_from(n).unwrap_or(u32::MAX);
-		self.offset = self.offset.saturating_add(n);
+		self.offset = self.offset.saturating_add(n).min(self.count);
 		self.next()
 	}
+
+	fn last(mut self) -> Option<Self::Item> {
+		self.next_back()
+	}
+}
+
+/// Every entry is sought to before it is read, so a clone continues independently if `io`
+/// doesn't share its position with the original, as with positioned reads.
+impl<R, Io> Clone for Iter<R, Io>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R> + Clone,
+{
+	fn clone(&self) -> Self {
+		Self {
+			io: self.io.clone(),
+			offset: self.offset,
+			count: self.count,
+			first: self.first,
+			stop_on_error: self.stop_on_error,
+		}
+	}
+}
+
+impl<R, Io> DoubleEndedIterator for Iter<R, Io>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+{
+	fn next_back(&mut self) -> Option<Self::Item> {
+		(self.offset < self.count).then(|| {
+			self.count -= 1;
+			match self.first.take() {
+				Some(e) if self.count == 0 => Ok(e),
+				f => {
+					self.first = f;
+					let e = get(self.count, &mut self.io);
+					self.check(e)
+				}
+			}
+		})
+	}
 }
 
+/// Once exhausted the offset never goes below the count again.
+impl<R, Io> core::iter::FusedIterator for Iter<R, Io> where Io: FnMut(Op<'_>) -> Result<(), R> {}
+
 impl<R, Io> ExactSizeIterator for Iter<R, Io>
 where
 	Io: FnMut(Op<'_>) -> Result<(), R>,
 {
 	fn len(&self) -> usize {
-		(self.count - self.offset).try_into().unwrap()
+		self.count.saturating_sub(self.offset).try_into().unwrap()
 	}
 }
 
+/// Iterates over all entries in order with the caller performing the reads.
+///
+/// Instead of calling an `io` closure, [`EntryScanner::step`] returns each read it needs, which
+/// suits event loops that own the reader.
+#[derive(Clone, Debug)]
+pub struct EntryScanner {
+	offset: u32,
+	count: u32,
+	first: Option<Entry>,
+	buf: [u8; 12],
+	pending: bool,
+}
+
+#[derive(Debug)]
+pub enum Step<'a> {
+	/// Fill `buf` with the bytes at `offset` in the image before calling `step` again.
+	Read {
+		offset: u64,
+		buf: &'a mut [u8],
+	},
+	Entry(Entry),
+	/// All entries have been yielded.
+	Done,
+}
+
+impl EntryScanner {
+	/// Advance the scan.
+	///
+	/// Calling this after [`Step::Read`] takes the buffer as filled.
+	pub fn step(&mut self) -> Step<'_> {
+		if self.pending {
+			self.pending = false;
+			self.offset += 1;
+			return Step::Entry(parse_entry(self.buf));
+		}
+		if self.offset >= self.count {
+			return Step::Done;
+		}
+		if let Some(e) = self.first.take().filter(|_| self.offset == 0) {
+			self.offset += 1;
+			return Step::Entry(e);
+		}
+		self.pending = true;
+		Step::Read { offset: 16 + u64::from(self.offset) * 12, buf: &mut self.buf }
+	}
+
+	/// Forget a read that couldn't be performed, so the next step requests it again.
+	pub fn retry(&mut self) {
+		self.pending = false;
+	}
+
+	/// The amount of entries that have not been yielded yet.
+	pub fn remaining(&self) -> u32 {
+		self.count - self.offset
+	}
+}
+
+/// Retries failed I/O operations according to a policy.
+///
+/// The policy is called with each error and returns whether the operation should be tried again.
+/// A failed [`Op::Read`] or [`Op::Advance`] may have moved the position, so it is retried only
+/// after seeking back to where it started. That position is unknown until an [`Op::Seek`] has
+/// succeeded, so until then and after an error that isn't retried these fail immediately.
+pub struct Retrying<R, Io, P>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+	P: FnMut(&R) -> bool,
+{
+	io: Io,
+	policy: P,
+	/// The position after the last operation, if known.
+	pos: Option<u64>,
+}
+
+impl<R, Io, P> Retrying<R, Io, P>
+where
+	Io: FnMut(Op<'_>) -> Result<(), R>,
+	P: FnMut(&R) -> bool,
+{
+	pub fn new(io: Io, policy: P) -> Self {
+		Self { io, policy, pos: None }
+	}
+
+	/// Perform an operation, retrying it for as long as the policy allows.
+	pub fn op(&mut self, mut op: Op<'_>) -> Result<(), R> {
+		let start = self.pos.take();
+		let moves = matches!(op, Op::Read(_) | Op::Advance(_));
+		let mut seek_back = false;
+		loop {
+			let r = match &mut op {
+				_ if seek_back => (self.io)(Op::Seek(start.unwrap())),
+				Op::Seek(p) => (self.io)(Op::Seek(*p)),
+				Op::Advance(p) => (self.io)(Op::Advance(*p)),
+				Op::Read(b) => (self.io)(Op::Read(b)),
+				Op::Len(l) => (self.io)(Op::Len(l)),
+			};
+			match r {
+				Ok(()) if seek_back => seek_back = false,
+				Ok(()) => break,
+				Err(e) if (self.policy)(&e) && (!moves || start.is_some()) => seek_back = moves,
+				Err(e) => return Err(e),
+			}
+		}
+		self.pos = match op {
+			Op::Seek(p) => Some(p),
+			Op::Advance(d) => start.and_then(|p| p.checked_add_signed(d)),
+			Op::Read(b) => start.map(|p| p + b.len() as u64),
+			Op::Len(_) => start,
+		};
+		Ok(())
+	}
+
+	pub fn into_inner(self) -> Io {
+		self.io
+	}
+}
+
+fn cmp_prefix(name: &[u8], prefix: &[u8], last: u8) -> Ordering {
+	name.iter().cmp(prefix.iter().chain(iter::once(&last)))
+}
+
 fn get<R, Io>(index: u32, mut io: Io) -> Result<Entry, R>
 where
 	Io: FnMut(Op<'_>) -> Result<(), R>,
@@ -168,3 +1220,1019 @@ where
 	io(Op::Read(&mut b))?;
 	Ok(parse_entry(b))
 }
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+	use super::*;
+	use alloc::{string::ToString, vec::Vec};
+
+	/// Perform each operation on an image in memory. Reads past the end fail.
+	pub(crate) fn slice_io(img: &[u8]) -> impl FnMut(Op<'_>) -> Result<(), ()> + '_ {
+		let mut pos = 0;
+		move |op| {
+			match op {
+				Op::Seek(p) => pos = usize::try_from(p).map_err(|_| ())?,
+				Op::Advance(d) => pos = pos.checked_add_signed(d as isize).ok_or(())?,
+				Op::Read(b) => {
+					b.copy_from_slice(img.get(pos..).and_then(|i| i.get(..b.len())).ok_or(())?);
+					pos += b.len();
+				}
+				Op::Len(l) => *l = img.len() as u64,
+			}
+			Ok(())
+		}
+	}
+
+	/// Read an image in memory from the start, as used to load the header.
+	pub(crate) fn read_io(img: &[u8]) -> impl FnMut(&mut [u8]) -> Result<(), ()> + '_ {
+		let mut io = slice_io(img);
+		move |b| io(Op::Read(b))
+	}
+
+	pub(crate) fn load(img: &[u8]) -> Header {
+		Header::load(read_io(img)).unwrap()
+	}
+
+	/// Build an image with the default block size.
+	pub(crate) fn image(files: &[(&str, &[u8])]) -> Vec<u8> {
+		let mut w = Writer::default();
+		for (name, data) in files {
+			w.add(name.as_bytes(), data).unwrap();
+		}
+		w.finish().unwrap()
+	}
+
+	#[test]
+	fn parse_header_error_display() {
+		let e = |e: ParseHeaderError<fmt::Error>| e.to_string();
+		assert_eq!(e(ParseHeaderError::BadMagic), "bad magic");
+		let v = ParseHeaderError::UnsupportedVersion { found: 3, supported: 0..=1 };
+		assert_eq!(e(v), "unsupported version 3, expected 0 to 1");
+		assert_eq!(e(ParseHeaderError::InvalidBlockSize), "invalid block size");
+		assert_eq!(
+			e(ParseHeaderError::UnknownFlags(0x84)),
+			"unknown flags 0x84"
+		);
+		assert_eq!(
+			e(ParseHeaderError::Truncated),
+			"image too short for header or entry table"
+		);
+		assert_eq!(
+			e(ParseHeaderError::Other(fmt::Error)),
+			fmt::Error.to_string()
+		);
+	}
+
+	#[test]
+	fn parse_header_error_source() {
+		use core::error::Error;
+		assert!(ParseHeaderError::Other(fmt::Error).source().is_some());
+		assert!(ParseHeaderError::<fmt::Error>::BadMagic.source().is_none());
+	}
+
+	/// FNV-1a over everything written, to compare hashes without std.
+	#[derive(Default)]
+	struct Fnv(u64);
+
+	impl core::hash::Hasher for Fnv {
+		fn finish(&self) -> u64 {
+			self.0
+		}
+
+		fn write(&mut self, bytes: &[u8]) {
+			for &c in bytes {
+				self.0 = (self.0 ^ u64::from(c)).wrapping_mul(0x100_0000_01b3);
+			}
+		}
+	}
+
+	fn hash(v: &impl core::hash::Hash) -> u64 {
+		let mut h = Fnv::default();
+		v.hash(&mut h);
+		core::hash::Hasher::finish(&h)
+	}
+
+	#[test]
+	fn entry_eq_hash() {
+		let img = image(&[("a", b"1"), ("b", b"2")]);
+		let h = load(&img);
+		let mut io = slice_io(&img);
+		let a = h.get(0, &mut io).unwrap().unwrap();
+		let b = h.get(1, &mut io).unwrap().unwrap();
+		assert_eq!(a, h.get(0, &mut io).unwrap().unwrap());
+		assert_eq!(hash(&a), hash(&h.get(0, &mut io).unwrap().unwrap()));
+		assert_ne!(a, b);
+	}
+
+	#[test]
+	fn header_copy() {
+		fn copy<T: Copy>(v: &T) -> T {
+			*v
+		}
+		let img = image(&[("a", b"1")]);
+		let h = Header::load_prefetch(slice_io(&img)).unwrap();
+		let c = copy(&h);
+		let e = copy(&c.get(0, slice_io(&img)).unwrap().unwrap());
+		assert_eq!(Some(e), h.first);
+		assert_eq!(
+			(c.file_count(), c.block_size()),
+			(h.file_count(), h.block_size())
+		);
+	}
+
+	#[test]
+	fn entry_bytes_round_trip() {
+		let e = Entry { filename_addr: 0x0102_0304, block_addr: u32::MAX, file_size: 7 };
+		let b = e.to_le_bytes();
+		assert_eq!(b, [4, 3, 2, 1, 0xff, 0xff, 0xff, 0xff, 7, 0, 0, 0]);
+		assert_eq!(parse_entry(b), e);
+		let img = image(&[("a", b"1"), ("b", b"2")]);
+		let e = get(1, slice_io(&img)).unwrap();
+		assert_eq!(e.to_le_bytes(), img[28..40]);
+	}
+
+	#[test]
+	fn block_size() {
+		assert_eq!(BlockSize::new(32), None);
+		let bs = BlockSize::new(31).unwrap();
+		assert_eq!(
+			(bs.as_shift(), bs.as_bytes(), bs.mask()),
+			(31, 1 << 31, (1 << 31) - 1)
+		);
+		let bs = BlockSize::new(0).unwrap();
+		assert_eq!((bs.as_bytes(), bs.mask()), (1, 0));
+	}
+
+	#[test]
+	fn block_size_padding() {
+		let bs = BlockSize::new(9).unwrap();
+		assert_eq!(bs.padding_for(0), 0);
+		assert_eq!(bs.padding_for(1), 511);
+		assert_eq!(bs.padding_for(512), 0);
+		assert_eq!(bs.padding_for(513), 511);
+		assert_eq!(bs.padding_for(u64::MAX), 1);
+		assert_eq!(BlockSize::new(0).unwrap().padding_for(12345), 0);
+		assert!(BlockSize::new(8) < BlockSize::new(9));
+		assert_eq!(BlockSize::new(1).max(BlockSize::new(4)), BlockSize::new(4));
+	}
+
+	#[test]
+	fn load_rejects_invalid_block_size() {
+		let mut img = image(&[]);
+		img[9] = 32;
+		let r = Header::load(read_io(&img));
+		assert!(matches!(r, Err(ParseHeaderError::InvalidBlockSize)));
+	}
+
+	#[test]
+	fn load_rejects_unknown_flags() {
+		let mut img = image(&[]);
+		img[10] |= 1 << 2;
+		let r = Header::load(read_io(&img));
+		assert!(matches!(r, Err(ParseHeaderError::UnknownFlags(0x05))));
+		let r = Header::load_lenient(read_io(&img));
+		assert!(matches!(r, Err(ParseHeaderError::UnknownFlags(_))));
+	}
+
+	#[test]
+	fn metadata() {
+		let mut w = Writer::default();
+		w.set_metadata(b"comment", b"hello").unwrap();
+		w.set_metadata(b"built", b"today").unwrap();
+		let img = w.finish().unwrap();
+		let h = load(&img);
+		let mut m = h.metadata(slice_io(&img)).unwrap();
+		let (mut k, mut v) = ([0; 255], [0; 255]);
+		let mut pairs = Vec::new();
+		while let Some(kv) = m.next(&mut k, &mut v) {
+			let (k, v) = kv.unwrap();
+			pairs.push((k.to_vec(), v.to_vec()));
+		}
+		let pairs = pairs
+			.iter()
+			.map(|(k, v)| (&k[..], &v[..]))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			pairs,
+			[
+				(&b"built"[..], &b"today"[..]),
+				(b"comment", b"hello"),
+				(b"tool", b"nrofs"),
+				(b"version", env!("CARGO_PKG_VERSION").as_bytes()),
+			]
+		);
+	}
+
+	#[test]
+	fn no_metadata() {
+		let mut w = Writer::default();
+		w.remove_metadata(b"tool");
+		w.remove_metadata(b"version");
+		let img = w.finish().unwrap();
+		let h = load(&img);
+		let mut m = h.metadata(slice_io(&img)).unwrap();
+		assert!(m.next(&mut [0; 255], &mut [0; 255]).is_none());
+	}
+
+	#[test]
+	fn load_prefetch() {
+		let img = image(&[("a", b"1"), ("b", b"2")]);
+		let h = Header::load_prefetch(slice_io(&img)).unwrap();
+		let fresh = get(0, slice_io(&img)).unwrap();
+		let no_io = |_: Op<'_>| -> Result<(), ()> { panic!("unexpected I/O") };
+		assert_eq!(h.get(0, no_io).unwrap().unwrap(), fresh);
+		assert_eq!(h.iter(no_io).next().unwrap().unwrap(), fresh);
+		assert_eq!(h.get(1, slice_io(&img)).unwrap(), get(1, slice_io(&img)));
+		let mut reads = 0;
+		let mut io = slice_io(&img);
+		Header::load_prefetch(|op: Op<'_>| {
+			reads += usize::from(matches!(op, Op::Read(_)));
+			io(op)
+		})
+		.unwrap();
+		assert_eq!(reads, 1);
+	}
+
+	#[test]
+	fn load_prefetch_empty() {
+		let img = image(&[]);
+		let h = Header::load_prefetch(slice_io(&img[..16])).unwrap();
+		assert_eq!(h.file_count(), 0);
+		// An image with entries must have room for the first.
+		let img = image(&[("a", b"")]);
+		let r = Header::load_prefetch(slice_io(&img[..20]));
+		assert!(matches!(r, Err(ParseHeaderError::Other(()))));
+	}
+
+	/// Fails every third operation, starting with the first, after doing half of a read.
+	fn flaky(img: &[u8]) -> impl FnMut(Op<'_>) -> Result<(), &'static str> + '_ {
+		let mut io = slice_io(img);
+		let mut n = 0;
+		move |op| {
+			let fail = n % 3 == 0;
+			n += 1;
+			match op {
+				Op::Read(b) if fail => {
+					let n = b.len() / 2;
+					let _ = io(Op::Read(&mut b[..n]));
+					Err("flaky")
+				}
+				_ if fail => Err("flaky"),
+				op => io(op).map_err(|()| "eof"),
+			}
+		}
+	}
+
+	#[test]
+	fn retrying_read() {
+		let img = image(&[("a", b"1"), ("b", b"2")]);
+		let h = load(&img);
+		let mut tries = 0;
+		let mut r = Retrying::new(flaky(&img), |_| {
+			tries += 1;
+			true
+		});
+		let e = h.get(1, |op| r.op(op)).unwrap().unwrap();
+		assert_eq!(e, get(1, slice_io(&img)).unwrap());
+		let mut buf = [0; 255];
+		assert_eq!(e.name(&mut buf, |op| r.op(op)).unwrap(), b"b");
+		// Without seeking in between, so a retry must not continue after the partial read.
+		let mut b = [0; 16];
+		r.op(Op::Seek(0)).unwrap();
+		for c in b.chunks_mut(4) {
+			r.op(Op::Read(c)).unwrap();
+		}
+		assert_eq!(b, img[..16]);
+		r.op(Op::Advance(-8)).unwrap();
+		r.op(Op::Read(&mut b[..8])).unwrap();
+		assert_eq!(b[..8], img[8..16]);
+		drop(r);
+		assert!(tries > 0);
+	}
+
+	#[test]
+	fn retrying_gives_up() {
+		let img = image(&[]);
+		let mut r = Retrying::new(flaky(&img), |_| false);
+		assert_eq!(r.op(Op::Seek(0)), Err("flaky"));
+		let mut r = Retrying::new(flaky(&img), |e| *e == "flaky");
+		// The position is unknown before the first seek.
+		assert_eq!(r.op(Op::Read(&mut [0; 4])), Err("flaky"));
+		let mut b = [0; 8];
+		r.op(Op::Seek(0)).unwrap();
+		r.op(Op::Read(&mut b)).unwrap();
+		assert_eq!(b, MAGIC);
+		assert_eq!(r.op(Op::Read(&mut [0; 4096])), Err("eof"));
+	}
+
+	#[test]
+	fn data_region_end() {
+		let img = image(&[("a", &[1; 5000]), ("b", b"1")]);
+		let h = load(&img);
+		let b = get(1, slice_io(&img)).unwrap();
+		assert_eq!(h.data_region_end(slice_io(&img)).unwrap(), b.offset(&h) + 1);
+		let mut padded = img.clone();
+		padded.resize(padded.len() + 100, 0);
+		assert_eq!(
+			h.data_region_end(slice_io(&padded)).unwrap(),
+			b.offset(&h) + 1
+		);
+	}
+
+	#[test]
+	fn resolve_path() {
+		for index in [false, true] {
+			let mut w = Writer::default();
+			w.set_path_index(index);
+			for name in ["a", "b/c", "d"] {
+				w.add(name.as_bytes(), b"").unwrap();
+			}
+			let img = w.finish().unwrap();
+			let h = load(&img);
+			assert_eq!(h.has_path_index(), index);
+			assert_eq!(h.resolve_path(b"b/c", slice_io(&img)), Ok(Some(1)));
+			assert_eq!(h.resolve_path(b"b", slice_io(&img)), Ok(None));
+		}
+	}
+
+	#[test]
+	fn data_region_end_no_files() {
+		let mut w = Writer::default();
+		w.set_path_index(true);
+		let img = w.finish().unwrap();
+		let h = load(&img);
+		assert!(img.len() as u64 > h.data_region_start());
+		assert_eq!(h.data_region_end(slice_io(&img)).unwrap(), img.len() as u64);
+		let mut w = Writer::default();
+		w.remove_metadata(b"tool");
+		w.remove_metadata(b"version");
+		let img = w.finish().unwrap();
+		assert_eq!(load(&img).data_region_end(slice_io(&img)).unwrap(), 16);
+	}
+
+	#[test]
+	fn name_into() {
+		let img = image(&[("abcdef", b"")]);
+		let e = get(0, slice_io(&img)).unwrap();
+		let mut buf = [0; 300];
+		assert_eq!(e.name_into(&mut buf, slice_io(&img)).unwrap(), b"abcdef");
+		let mut buf = [0; 6];
+		assert_eq!(e.name_into(&mut buf, slice_io(&img)).unwrap(), b"abcdef");
+		let mut buf = [0; 5];
+		let r = e.name_into(&mut buf, slice_io(&img));
+		assert!(matches!(r, Err(EntryError::NameTooLong(6))));
+	}
+
+	#[test]
+	fn stop_on_error() {
+		let img = image(&[("a", b""), ("b", b""), ("c", b"")]);
+		let h = load(&img);
+		// Reading the second entry fails.
+		let io = || {
+			let mut io = slice_io(&img);
+			move |op: Op<'_>| match op {
+				Op::Seek(28) => Err(()),
+				op => io(op),
+			}
+		};
+		let ok = |r: Option<Result<Entry, ()>>| r.map(|e| e.is_ok());
+		let mut it = h.iter(io());
+		assert_eq!(
+			[ok(it.next()), ok(it.next()), ok(it.next())],
+			[Some(true), Some(false), Some(true)]
+		);
+		let mut it = h.iter(io()).stop_on_error();
+		assert_eq!(
+			[ok(it.next()), ok(it.next()), ok(it.next())],
+			[Some(true), Some(false), None]
+		);
+		assert_eq!(it.len(), 0);
+		let mut it = h.iter(io()).stop_on_error();
+		assert_eq!(
+			[
+				ok(it.next_back()),
+				ok(it.next_back()),
+				ok(it.next_back()),
+				ok(it.next())
+			],
+			[Some(true), Some(false), None, None]
+		);
+	}
+
+	#[test]
+	fn load_versions() {
+		let mut img = image(&[]);
+		assert!(Header::load_versions(read_io(&img), 0..=0).is_ok());
+		img[8] = LATEST_VERSION + 1;
+		let r = Header::load_versions(read_io(&img), 0..=u8::MAX);
+		assert!(matches!(
+			r,
+			Err(ParseHeaderError::UnsupportedVersion { found, supported })
+				if found == LATEST_VERSION + 1 && supported == (0..=LATEST_VERSION)
+		));
+		assert!(matches!(
+			Header::load(read_io(&img)),
+			Err(ParseHeaderError::UnsupportedVersion { .. })
+		));
+	}
+
+	#[test]
+	fn entry_scanner() {
+		let img = image(&[("a", b"1"), ("b", b"2"), ("c", b"3")]);
+		let expect = (0..3).map(|i| get(i, slice_io(&img)).unwrap());
+		let mut s = load(&img).scanner();
+		assert_eq!(s.remaining(), 3);
+		let mut entries = Vec::new();
+		let mut reads = Vec::new();
+		let mut failed = false;
+		loop {
+			match s.step() {
+				Step::Read { offset, buf } => {
+					reads.push(offset);
+					// The first attempt at the second entry fails.
+					if offset == 28 && !failed {
+						failed = true;
+						s.retry();
+						continue;
+					}
+					buf.copy_from_slice(&img[offset as usize..][..buf.len()]);
+				}
+				Step::Entry(e) => entries.push(e),
+				Step::Done => break,
+			}
+		}
+		assert!(entries.into_iter().eq(expect));
+		assert_eq!(reads, [16, 28, 28, 40]);
+		assert_eq!(s.remaining(), 0);
+		assert!(matches!(s.step(), Step::Done));
+		// A prefetched first entry needs no read.
+		let mut s = Header::load_prefetch(slice_io(&img)).unwrap().scanner();
+		assert!(matches!(s.step(), Step::Entry(e) if e == get(0, slice_io(&img)).unwrap()));
+		assert!(matches!(s.step(), Step::Read { offset: 28, .. }));
+	}
+
+	/// Every child of `dir` with its name, or `None` for each error.
+	fn list<Io>(h: &Header, dir: &str, io: Io) -> Vec<Option<(Vec<u8>, DirChild)>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), ()>,
+	{
+		let mut d = h.read_dir(dir.as_bytes(), io).unwrap();
+		let mut buf = [0; 255];
+		let mut v = Vec::new();
+		while let Some(c) = d.next(&mut buf) {
+			v.push(c.ok().map(|(n, c)| (n.to_vec(), c)));
+		}
+		v
+	}
+
+	#[test]
+	fn read_dir() {
+		let img = image(&[
+			("boot/efi/grub.cfg", b""),
+			("boot/efi/x/y", b""),
+			("boot/initrd", b""),
+			("boot/vmlinuz", b""),
+			("bootx", b""),
+			("etc/fstab", b""),
+		]);
+		let h = load(&img);
+		let e = |i| DirChild::File(i, get(i, slice_io(&img)).unwrap());
+		let boot = [
+			Some((b"efi".to_vec(), DirChild::Dir(0..2))),
+			Some((b"initrd".to_vec(), e(2))),
+			Some((b"vmlinuz".to_vec(), e(3))),
+		];
+		for dir in ["boot", "boot/", "boot//"] {
+			assert_eq!(list(&h, dir, slice_io(&img)), boot, "{:?}", dir);
+		}
+		assert_eq!(
+			list(&h, "", slice_io(&img)),
+			[
+				Some((b"boot".to_vec(), DirChild::Dir(0..4))),
+				Some((b"bootx".to_vec(), e(4))),
+				Some((b"etc".to_vec(), DirChild::Dir(5..6))),
+			]
+		);
+		assert_eq!(
+			list(&h, "boot/efi", slice_io(&img)),
+			[
+				Some((b"grub.cfg".to_vec(), e(0))),
+				Some((b"x".to_vec(), DirChild::Dir(1..2))),
+			]
+		);
+		assert!(list(&h, "usr", slice_io(&img)).is_empty());
+		assert!(list(&h, "boot/initrd", slice_io(&img)).is_empty());
+		// The listing continues past an entry that can't be read.
+		let fail = core::cell::Cell::new(false);
+		let mut io = slice_io(&img);
+		let broken = |op: Op<'_>| match op {
+			Op::Seek(p) if p == 16 + 2 * 12 && fail.get() => Err(()),
+			op => io(op),
+		};
+		let mut d = h.read_dir(b"boot", broken).unwrap();
+		let mut buf = [0; 255];
+		let mut next = || {
+			d.next(&mut buf)
+				.map(|c| c.ok().map(|(n, c)| (n.to_vec(), c)))
+		};
+		assert_eq!(next(), Some(boot[0].clone()));
+		fail.set(true);
+		assert_eq!(
+			[next(), next(), next()],
+			[Some(None), Some(boot[2].clone()), None]
+		);
+	}
+
+	#[test]
+	fn load_all_entries() {
+		for files in [0, 3, 5000] {
+			let names = (0..files)
+				.map(|i| alloc::format!("{:04}", i))
+				.collect::<Vec<_>>();
+			let img = image(&names.iter().map(|n| (&**n, &b""[..])).collect::<Vec<_>>());
+			let h = load(&img);
+			let mut io = slice_io(&img);
+			let mut reads = 0;
+			let entries = h
+				.load_all_entries(|op: Op<'_>| {
+					reads += usize::from(matches!(op, Op::Read(_)));
+					io(op)
+				})
+				.unwrap();
+			let expect = (0..files).map(|i| h.get(i, slice_io(&img)).unwrap().unwrap());
+			assert!(entries.into_iter().eq(expect));
+			assert_eq!(reads, (files as usize).div_ceil(4096));
+		}
+	}
+
+	#[test]
+	fn load_all_entries_bogus_count() {
+		let mut img = image(&[]);
+		img.truncate(16);
+		img[12..].copy_from_slice(&u32::MAX.to_le_bytes());
+		let h = load(&img);
+		assert_eq!(h.load_all_entries(slice_io(&img)), Err(()));
+		let r = Tree::new(&h, 16, slice_io(&img));
+		assert!(matches!(r, Err(TreeError::Other(()))));
+	}
+
+	#[test]
+	fn find_range() {
+		let img = image(&[
+			("a", b""),
+			("a.b", b""),
+			("a/b", b""),
+			("a/b/c", b""),
+			("a/c", b""),
+			("a0", b""),
+			("b/a", b""),
+		]);
+		let h = load(&img);
+		let r = |dir: &str| h.find_range(dir.as_bytes(), slice_io(&img)).unwrap();
+		assert_eq!(r("a"), 2..5);
+		assert_eq!(r("a//"), 2..5);
+		assert_eq!(r("a/b"), 3..4);
+		assert_eq!(r("b"), 6..7);
+		assert_eq!(r(""), 0..7);
+		assert!(r("a/c").is_empty());
+		assert!(r("c").is_empty());
+	}
+
+	#[test]
+	fn load_with_len() {
+		let img = image(&[("a", b"1"), ("b", b"2")]);
+		let start = load(&img).data_region_start();
+		assert!(Header::load_with_len(read_io(&img), start).is_ok());
+		let r = Header::load_with_len(read_io(&img), start - 1);
+		assert!(matches!(r, Err(ParseHeaderError::Truncated)));
+	}
+
+	#[test]
+	fn nth_name() {
+		let img = image(&[("a", b""), ("bc", b"")]);
+		let h = load(&img);
+		let mut buf = [0; 255];
+		assert_eq!(
+			h.nth_name(1, &mut buf, slice_io(&img)),
+			Some(Ok(&b"bc"[..]))
+		);
+		assert_eq!(h.nth_name(0, &mut buf, slice_io(&img)), Some(Ok(&b"a"[..])));
+		assert_eq!(h.nth_name(2, &mut buf, slice_io(&img)), None);
+		assert_eq!(h.nth_name(u32::MAX, &mut buf, slice_io(&img)), None);
+	}
+
+	/// Perform operations on an image in memory at a position shared by every copy.
+	fn shared_io<'a>(
+		img: &'a [u8],
+		pos: &'a core::cell::Cell<u64>,
+	) -> impl FnMut(Op<'_>) -> Result<(), ()> + Copy + 'a {
+		move |op| {
+			let mut io = slice_io(img);
+			io(Op::Seek(pos.get()))?;
+			match op {
+				Op::Seek(p) => pos.set(p),
+				Op::Advance(d) => pos.set(pos.get().checked_add_signed(d).ok_or(())?),
+				Op::Read(b) => {
+					io(Op::Read(b))?;
+					pos.set(pos.get() + b.len() as u64);
+				}
+				Op::Len(l) => io(Op::Len(l))?,
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn iter_interleaved_io() {
+		let img = image(&[("a", b"1"), ("b", b"2"), ("c", b"3")]);
+		let h = load(&img);
+		let pos = core::cell::Cell::new(0);
+		let mut io = shared_io(&img, &pos);
+		let mut entries = Vec::new();
+		for e in h.iter(io) {
+			entries.push(e.unwrap());
+			// Read the name and some data in between, leaving the position elsewhere.
+			let mut buf = [0; 255];
+			entries.last().unwrap().name(&mut buf, io).unwrap();
+			io(Op::Seek(0)).unwrap();
+			io(Op::Read(&mut [0; 5])).unwrap();
+		}
+		let expect = (0..3).map(|i| get(i, slice_io(&img)).unwrap());
+		assert!(entries.into_iter().eq(expect));
+	}
+
+	#[test]
+	fn iter_interleaved_io_back_and_nth() {
+		let img = image(&[("a", b"1"), ("b", b"2"), ("c", b"3"), ("d", b"4")]);
+		let h = load(&img);
+		let pos = core::cell::Cell::new(0);
+		let mut io = shared_io(&img, &pos);
+		let e = |i| Some(get(i, slice_io(&img)).unwrap());
+		let mut it = h.iter(io);
+		let mut elsewhere = || {
+			io(Op::Seek(img.len() as u64 - 3)).unwrap();
+			io(Op::Read(&mut [0; 2])).unwrap();
+		};
+		assert_eq!(it.next_back().map(Result::unwrap), e(3));
+		elsewhere();
+		assert_eq!(it.nth(1).map(Result::unwrap), e(1));
+		elsewhere();
+		assert_eq!(it.next_back().map(Result::unwrap), e(2));
+		elsewhere();
+		assert!(it.next().is_none());
+		elsewhere();
+		assert_eq!(h.get(2, io).unwrap(), get(2, slice_io(&img)));
+	}
+
+	#[test]
+	fn entries_in_range() {
+		let img = image(&[
+			("a", &[1; 10]),
+			("b", &[2; 5000]),
+			("c", b""),
+			("d", b"xyz"),
+		]);
+		let h = load(&img);
+		let r = |i| h.get(i, slice_io(&img)).unwrap().unwrap().data_range(&h);
+		let (a, b, d) = (r(0), r(1), r(3));
+		let f = |range| h.entries_in_range(range, slice_io(&img)).unwrap();
+		assert_eq!(f(a.start + 5..b.start + 1), [0, 1]);
+		assert_eq!(f(a.start..d.end), [0, 1, 3]);
+		// The padding after a file belongs to no entry.
+		assert_eq!(f(a.end..b.start), []);
+		assert_eq!(f(b.start..b.start), []);
+	}
+
+	#[test]
+	fn name_len() {
+		let long = "n".repeat(255);
+		let img = image(&[("", b""), ("a", b"1"), ("dir/bc", b""), (&long, b"")]);
+		let h = load(&img);
+		for e in h.iter(slice_io(&img)) {
+			let e = e.unwrap();
+			let len = e.name_len(slice_io(&img)).unwrap();
+			assert_eq!(
+				usize::from(len),
+				e.name(&mut [0; 255], slice_io(&img)).unwrap().len()
+			);
+		}
+	}
+
+	#[test]
+	fn data_region_start() {
+		let img = image(&[("a", b"1"), ("b", b"2"), ("c", b"")]);
+		let h = load(&img);
+		assert_eq!(h.data_region_start(), 16 + 3 * 12);
+		for e in h.iter(slice_io(&img)) {
+			assert!(e.unwrap().offset(&h) >= h.data_region_start());
+		}
+	}
+
+	#[test]
+	fn last_entry() {
+		for files in [
+			&[][..],
+			&[("a", &b"1"[..])],
+			&[("a", b"1"), ("b", b"22"), ("c", b"")],
+		] {
+			let img = image(files);
+			let h = load(&img);
+			assert_eq!(h.last_entry(slice_io(&img)), h.iter(slice_io(&img)).last());
+		}
+		assert_eq!(load(&image(&[])).last_entry(slice_io(&[])), None);
+	}
+
+	#[test]
+	fn complete_prefix() {
+		let img = image(&[("ab", b""), ("abc", b""), ("abd/e", b""), ("b", b"")]);
+		let h = load(&img);
+		let c = |p: &str, max| -> Vec<Vec<u8>> {
+			h.complete_prefix(p.as_bytes(), max, slice_io(&img))
+				.unwrap()
+		};
+		assert!(c("x", 10).is_empty());
+		assert!(c("abz", 10).is_empty());
+		assert_eq!(c("ab", 10), [&b"ab"[..], b"abc", b"abd/e"]);
+		assert_eq!(c("ab", 2), [&b"ab"[..], b"abc"]);
+		assert_eq!(c("abc", 10), [b"abc"]);
+		assert_eq!(c("", 10).len(), 4);
+	}
+
+	/// Keeps the trace messages of each thread, so tests running in parallel don't mix.
+	#[cfg(all(feature = "log", feature = "std"))]
+	struct Capture;
+
+	#[cfg(all(feature = "log", feature = "std"))]
+	std::thread_local! {
+		static TRACES: core::cell::RefCell<Vec<alloc::string::String>> = Default::default();
+	}
+
+	#[cfg(all(feature = "log", feature = "std"))]
+	impl log::Log for Capture {
+		fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+			true
+		}
+
+		fn log(&self, record: &log::Record<'_>) {
+			if record.target() == "nrofs" {
+				TRACES.with(|t| t.borrow_mut().push(record.args().to_string()));
+			}
+		}
+
+		fn flush(&self) {}
+	}
+
+	#[cfg(all(feature = "log", feature = "std"))]
+	#[test]
+	fn trace() {
+		log::set_logger(&Capture).unwrap();
+		log::set_max_level(log::LevelFilter::Trace);
+		let img = image(&[("a", b"1"), ("b", b"")]);
+		let h = load(&img);
+		for e in h.iter(slice_io(&img)) {
+			e.unwrap();
+		}
+		let traces = TRACES.with(|t| t.take());
+		assert_eq!(traces[0], "header: block size 4096, flags 0x1, 2 files");
+		let entries = traces.iter().filter(|t| t.starts_with("entry: "));
+		assert_eq!(entries.count(), 2);
+		assert_eq!(traces.len(), 3);
+	}
+
+	#[test]
+	fn entry_at_offset() {
+		let img = image(&[("a", &[1; 10]), ("b", &[2; 5000]), ("c", b"")]);
+		let h = load(&img);
+		let r = |i| h.get(i, slice_io(&img)).unwrap().unwrap().data_range(&h);
+		let at = |o| h.entry_at_offset(o, slice_io(&img)).unwrap();
+		let (a, b) = (r(0), r(1));
+		assert_eq!(at(a.start), Some(0));
+		assert_eq!(at(a.end - 1), Some(0));
+		assert_eq!(at(b.start + 4096), Some(1));
+		// Padding, the header and entry table, the metadata section and anything past the end.
+		assert_eq!(at(a.end), None);
+		assert_eq!(at(0), None);
+		assert_eq!(at(h.data_region_start()), None);
+		assert_eq!(at(b.end), None);
+		assert_eq!(at(u64::MAX), None);
+	}
+
+	#[test]
+	fn name_from_slice() {
+		let img = image(&[("a", b"1"), ("dir/bc", b""), (&"n".repeat(255), b"")]);
+		let h = load(&img);
+		for e in h.iter(slice_io(&img)) {
+			let e = e.unwrap();
+			let mut buf = [0; 255];
+			let name = e.name(&mut buf, slice_io(&img)).unwrap();
+			assert_eq!(e.name_from_slice(&img), Some(name));
+			// Cut off in the middle of the name and before its length.
+			let start = e.filename_addr as usize;
+			assert_eq!(e.name_from_slice(&img[..start + name.len()]), None);
+			assert_eq!(e.name_from_slice(&img[..start]), None);
+		}
+	}
+
+	#[test]
+	fn get_checked() {
+		let img = image(&[("a", b"1"), ("b", b"22")]);
+		let h = load(&img);
+		let check = |img: &[u8], i| h.get_checked(i, slice_io(img)).unwrap();
+		assert_eq!(check(&img, 1).ok(), h.get(1, slice_io(&img)).unwrap().ok());
+		assert!(h.get_checked(2, slice_io(&img)).is_none());
+		// Corrupt the name address, block address and size of the second entry in turn.
+		for (field, value) in [(28, img.len() as u32), (32, u32::MAX), (36, u32::MAX)] {
+			let mut bad = img.clone();
+			bad[field..field + 4].copy_from_slice(&value.to_le_bytes());
+			assert!(
+				matches!(check(&bad, 1), Err(EntryError::Corrupt)),
+				"{}",
+				field
+			);
+			assert!(check(&bad, 0).is_ok());
+		}
+		// Data ending exactly at the end of the image is fine.
+		let mut bad = img.clone();
+		let e = h.get(1, slice_io(&img)).unwrap().unwrap();
+		let size = (img.len() as u64 - e.offset(&h)) as u32;
+		bad[36..40].copy_from_slice(&size.to_le_bytes());
+		assert!(check(&bad, 1).is_ok());
+		bad[36..40].copy_from_slice(&(size + 1).to_le_bytes());
+		assert!(matches!(check(&bad, 1), Err(EntryError::Corrupt)));
+	}
+
+	#[test]
+	fn iter_filter() {
+		let img = image(&[("a", b"1"), ("b", b""), ("c", b"333"), ("d", b"")]);
+		let h = load(&img);
+		let sizes = h.iter_filter(slice_io(&img), |e| e.size() > 0);
+		assert_eq!(sizes.map(|e| e.unwrap().size()).collect::<Vec<_>>(), [1, 3]);
+		// Errors are yielded even though no entry would pass.
+		assert!(h
+			.iter_filter(slice_io(&img[..30]), |_| false)
+			.all(|e| e.is_err()));
+		assert_eq!(h.iter_filter(slice_io(&img[..30]), |_| false).count(), 3);
+	}
+
+	#[test]
+	fn write_name() {
+		let long = "n".repeat(254) + "x";
+		let img = image(&[(&long, b"")]);
+		let e = get(0, slice_io(&img)).unwrap();
+		let mut v = Vec::new();
+		e.write_name(slice_io(&img), |b| {
+			v.extend_from_slice(b);
+			Ok(())
+		})
+		.unwrap();
+		assert_eq!(v, long.as_bytes());
+		// An output that fills up stops the name early.
+		let (mut buf, mut n) = ([0; 100], 0);
+		let r = e.write_name(slice_io(&img), |b| {
+			let dst = buf.get_mut(n..n + b.len()).ok_or(())?;
+			dst.copy_from_slice(b);
+			n += b.len();
+			Ok(())
+		});
+		assert_eq!(r, Err(()));
+		assert_eq!(buf[..n], long.as_bytes()[..n]);
+	}
+
+	#[test]
+	fn load_lenient() {
+		let mut img = image(&[("a", b"1")]);
+		let (h, w) = Header::load_lenient(read_io(&img)).unwrap();
+		assert_eq!(w, Warnings::default());
+		assert_eq!(h.file_count(), 1);
+		img[0] ^= 0xff;
+		assert!(matches!(
+			Header::load(read_io(&img)),
+			Err(ParseHeaderError::BadMagic)
+		));
+		let (h, w) = Header::load_lenient(read_io(&img)).unwrap();
+		assert_eq!(w, Warnings { bad_magic: true, unsupported_version: false });
+		assert_eq!(h.get(0, slice_io(&img)).unwrap(), get(0, slice_io(&img)));
+		img[8] = 0xff;
+		let (_, w) = Header::load_lenient(read_io(&img)).unwrap();
+		assert_eq!(w, Warnings { bad_magic: true, unsupported_version: true });
+		// The fields after the version are still checked.
+		img[9] = 32;
+		let r = Header::load_lenient(read_io(&img));
+		assert!(matches!(r, Err(ParseHeaderError::InvalidBlockSize)));
+	}
+
+	#[test]
+	fn iter_nth_past_end() {
+		let img = image(&[("a", b"1"), ("b", b"2")]);
+		let h = load(&img);
+		let mut it = h.iter(slice_io(&img));
+		assert!(it.nth(usize::MAX).is_none());
+		assert_eq!(it.len(), 0);
+		assert!(it.next_back().is_none());
+		let mut it = h.iter(slice_io(&img));
+		it.next_back();
+		assert!(it.nth(usize::MAX).is_none());
+		assert_eq!(it.len(), 0);
+	}
+
+	#[test]
+	fn data_extent() {
+		let img = image(&[("a", b"hello"), ("b", b"")]);
+		let h = load(&img);
+		let e = get(0, slice_io(&img)).unwrap();
+		let (start, len) = e.data_extent(&h);
+		assert_eq!((start, len), (e.offset(&h), e.size()));
+		assert_eq!(&img[start as usize..][..len as usize], b"hello");
+		assert_eq!(get(1, slice_io(&img)).unwrap().data_extent(&h).1, 0);
+	}
+
+	#[test]
+	fn layout() {
+		let img = image(&[("a", b"hello"), ("b", &[0; 5000]), ("c", b"xyz")]);
+		let h = load(&img);
+		let layout = h
+			.layout(slice_io(&img))
+			.map(Result::unwrap)
+			.collect::<Vec<_>>();
+		let expect = h.iter(slice_io(&img)).zip(0..).map(|(e, i)| {
+			let e = e.unwrap();
+			(i, e.offset(&h), e.size())
+		});
+		assert!(layout.iter().copied().eq(expect));
+		assert_eq!(layout[1].2, 5000);
+		assert!(layout[0].1 < layout[1].1 && layout[1].1 < layout[2].1);
+	}
+
+	#[test]
+	fn for_each_name() {
+		let img = image(&[("b/c", b""), ("a", b"1"), ("d", b"")]);
+		let h = load(&img);
+		let mut names = Vec::new();
+		h.for_each_name(slice_io(&img), |n| {
+			names.push(n.to_vec());
+			Ok(())
+		})
+		.unwrap();
+		assert_eq!(names, [&b"a"[..], b"b/c", b"d"]);
+		// An error from `f` ends the walk.
+		let mut calls = 0;
+		let r = h.for_each_name(slice_io(&img), |_| {
+			calls += 1;
+			Err(())
+		});
+		assert_eq!((r, calls), (Err(()), 1));
+	}
+
+	#[test]
+	fn name_checked() {
+		let img = image(&[("abc", b""), ("d", b"")]);
+		let h = load(&img);
+		let e = get(0, slice_io(&img)).unwrap();
+		let mut buf = [0; 255];
+		assert_eq!(e.name_checked(&mut buf, slice_io(&img)).unwrap(), b"abc");
+		let start = e.filename_addr as usize;
+		// The name, its length and then its address run past the end in turn.
+		for end in [start + 3, start + 1, start] {
+			let r = e.name_checked(&mut buf, slice_io(&img[..end]));
+			assert!(matches!(r, Err(EntryError::Corrupt)), "{}", end);
+		}
+		// The checks pass if the io doesn't know the length.
+		fn unknown_len(img: &[u8]) -> impl FnMut(Op<'_>) -> Result<(), ()> + '_ {
+			let mut io = slice_io(img);
+			move |op| match op {
+				Op::Len(_) => Ok(()),
+				op => io(op),
+			}
+		}
+		assert_eq!(e.name_checked(&mut buf, unknown_len(&img)).unwrap(), b"abc");
+		assert!(h.get_checked(1, unknown_len(&img)).unwrap().is_ok());
+	}
+
+	#[test]
+	fn iter_clone() {
+		let img = image(&[("a", b"1"), ("b", b"2"), ("c", b"3"), ("d", b"4")]);
+		let h = Header::load_prefetch(slice_io(&img)).unwrap();
+		let pos = core::cell::Cell::new(0);
+		let e = |i| get(i, slice_io(&img)).unwrap();
+		let mut it = h.iter(shared_io(&img, &pos));
+		assert_eq!(it.next().unwrap().unwrap(), e(0));
+		let c = it.clone();
+		assert!(c.map(Result::unwrap).eq((1..4).map(e)));
+		// The original is where it was, even though the clone moved the shared position.
+		assert_eq!(it.len(), 3);
+		assert_eq!(it.next_back().unwrap().unwrap(), e(3));
+		let mut c = it.clone();
+		assert_eq!(it.next().unwrap().unwrap(), e(1));
+		assert_eq!(c.next().unwrap().unwrap(), e(1));
+		assert!(it.map(Result::unwrap).eq([e(2)]));
+		assert_eq!(c.len(), 1);
+	}
+
+	#[test]
+	fn load_rejects_bad_magic() {
+		let mut img = image(&[]);
+		img[0] = b'X';
+		let r = Header::load(read_io(&img));
+		assert!(matches!(r, Err(ParseHeaderError::BadMagic)));
+	}
+}
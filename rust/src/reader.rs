@@ -0,0 +1,111 @@
+//! Adapters for [`std::io`] readers.
+
+use crate::{Entry, Header, Iter, Op, ParseHeaderError};
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Perform each [`Op`] on a reader.
+///
+/// Short reads are retried. If the reader ends before a read is complete the error has kind
+/// [`io::ErrorKind::UnexpectedEof`], which tells a truncated image apart from other failures.
+pub fn reader_io<T>(r: &mut T) -> impl FnMut(Op<'_>) -> io::Result<()> + '_
+where
+	T: Read + Seek,
+{
+	move |op| match op {
+		Op::Seek(p) => r.seek(SeekFrom::Start(p)).map(|_| ()),
+		Op::Advance(p) => r.seek(SeekFrom::Current(p)).map(|_| ()),
+		Op::Read(b) => r.read_exact(b).map_err(truncated),
+		Op::Len(l) => {
+			let pos = r.stream_position()?;
+			*l = r.seek(SeekFrom::End(0))?;
+			r.seek(SeekFrom::Start(pos)).map(|_| ())
+		}
+	}
+}
+
+impl Header {
+	/// Load the header from the start of a reader.
+	///
+	/// A reader that ends before the header is complete gives [`ParseHeaderError::Truncated`].
+	pub fn from_reader<T>(r: &mut T) -> Result<Self, ParseHeaderError<io::Error>>
+	where
+		T: Read + Seek,
+	{
+		r.seek(SeekFrom::Start(0))
+			.map_err(ParseHeaderError::Other)?;
+		Self::load(|b| r.read_exact(b)).map_err(|e| match e {
+			ParseHeaderError::Other(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+				ParseHeaderError::Truncated
+			}
+			e => e,
+		})
+	}
+
+	/// Iterate over all entries in order, reading them from `r`.
+	pub fn iter_from_reader<'a, T>(
+		&self,
+		r: &'a mut T,
+	) -> Iter<io::Error, impl FnMut(Op<'_>) -> io::Result<()> + 'a>
+	where
+		T: Read + Seek,
+	{
+		self.iter(reader_io(r))
+	}
+}
+
+impl Entry {
+	/// Read the name from `r`.
+	pub fn name_from_reader<'a, T>(&self, buf: &'a mut [u8; 255], r: &mut T) -> io::Result<&'a [u8]>
+	where
+		T: Read + Seek,
+	{
+		self.name(buf, reader_io(r))
+	}
+}
+
+fn truncated(e: io::Error) -> io::Error {
+	if e.kind() == io::ErrorKind::UnexpectedEof {
+		io::Error::new(e.kind(), "image is truncated")
+	} else {
+		e
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::{image, slice_io};
+	use std::io::Cursor;
+
+	/// Asking for the length doesn't move the position of the next read.
+	fn len<R: std::fmt::Debug>(mut io: impl FnMut(Op<'_>) -> Result<(), R>, img: &[u8]) {
+		let (mut l, mut b) = (0, [0; 4]);
+		io(Op::Seek(3)).unwrap();
+		io(Op::Len(&mut l)).unwrap();
+		assert_eq!(l, img.len() as u64);
+		io(Op::Read(&mut b)).unwrap();
+		assert_eq!(b, img[3..7]);
+	}
+
+	#[test]
+	fn op_len() {
+		let img = image(&[("a", b"1")]);
+		len(reader_io(&mut Cursor::new(&img)), &img);
+		len(slice_io(&img), &img);
+	}
+
+	#[test]
+	fn from_reader_truncated() {
+		let img = image(&[("a", b"1")]);
+		for len in [0, 1, 15] {
+			let r = Header::from_reader(&mut Cursor::new(&img[..len]));
+			assert!(matches!(r, Err(ParseHeaderError::Truncated)), "{}", len);
+		}
+		let h = Header::from_reader(&mut Cursor::new(&img[..16])).unwrap();
+		assert_eq!(h.file_count(), 1);
+		// The header is read from the start regardless of the position.
+		let mut c = Cursor::new(&img);
+		c.set_position(20);
+		assert_eq!(Header::from_reader(&mut c).unwrap().file_count(), 1);
+	}
+}
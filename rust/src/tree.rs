@@ -0,0 +1,278 @@
+//! Directory tree built from the flat entry table.
+//!
+//! Paths are byte strings separated by `/`. Empty components are ignored, so leading,
+//! trailing and repeated slashes have no effect.
+
+use crate::{Entry, Header, Op};
+use alloc::{
+	boxed::Box,
+	collections::{btree_map, BTreeMap},
+	vec::Vec,
+};
+use core::fmt;
+
+/// Children of a directory sorted by name, with the start and length of each name in
+/// [`Tree::names`].
+type Dir = Box<[(u32, u8, Node)]>;
+
+/// All directories implied by the names of the entries in an image.
+///
+/// Directories are numbered in order of their first appearance in the entry table.
+/// Directory 0 is the root and subdirectories always come after their parent.
+#[derive(Debug)]
+pub struct Tree {
+	dirs: Vec<Dir>,
+	/// The names of all children, concatenated so they don't need an allocation each.
+	names: Vec<u8>,
+	entries: Vec<Entry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Node {
+	/// A file with the index of its entry.
+	File(u32),
+	/// A directory with its number in the [`Tree`].
+	Dir(u32),
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TreeError<R> {
+	/// The name of the entry with this index has more components than allowed.
+	TooDeep(u32),
+	/// The names of all children together don't fit in 32-bit offsets.
+	TooLarge,
+	Other(R),
+}
+
+impl Tree {
+	/// Build the tree from all entries, limiting paths to `max_depth` components.
+	///
+	/// A file with the same name as a directory is shadowed by it.
+	/// The entry table is read in large chunks with [`Header::load_all_entries`].
+	pub fn new<R, Io>(header: &Header, max_depth: usize, mut io: Io) -> Result<Self, TreeError<R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let mut dirs = alloc::vec![BTreeMap::<Box<[u8]>, Node>::default()];
+		let mut buf = [0; 255];
+		let entries = header.load_all_entries(&mut io).map_err(TreeError::Other)?;
+		for (i, e) in (0..).zip(&entries) {
+			let name = e.name(&mut buf, &mut io).map_err(TreeError::Other)?;
+			let mut di = 0;
+			let mut it = components(name).peekable();
+			let mut depth = 0;
+			while let Some(p) = it.next() {
+				depth += 1;
+				if depth > max_depth {
+					return Err(TreeError::TooDeep(i));
+				}
+				let l = dirs.len().try_into().unwrap();
+				let d = dirs[di as usize].entry(p.into());
+				if it.peek().is_some() {
+					// dir
+					di = match d {
+						btree_map::Entry::Occupied(mut d) => match *d.get() {
+							Node::Dir(n) => n,
+							Node::File(_) => {
+								d.insert(Node::Dir(l));
+								dirs.push(Default::default());
+								l
+							}
+						},
+						btree_map::Entry::Vacant(d) => {
+							d.insert(Node::Dir(l));
+							dirs.push(Default::default());
+							l
+						}
+					};
+				} else {
+					// file
+					d.or_insert(Node::File(i));
+				}
+			}
+		}
+		// Every name is a component of a name in the image, so it is at most 255 bytes long.
+		let mut names = Vec::new();
+		let dirs = dirs
+			.into_iter()
+			.map(|d| {
+				d.into_iter()
+					.map(|(k, v)| {
+						let start = u32::try_from(names.len()).map_err(|_| TreeError::TooLarge)?;
+						names.extend_from_slice(&k);
+						Ok((start, k.len() as u8, v))
+					})
+					.collect()
+			})
+			.collect::<Result<_, _>>()?;
+		Ok(Self { dirs, names, entries })
+	}
+
+	/// The entry of a file, which is kept so it can be used without I/O.
+	pub fn entry(&self, index: u32) -> Option<&Entry> {
+		self.entries.get(index as usize)
+	}
+
+	pub fn dir_count(&self) -> usize {
+		self.dirs.len()
+	}
+
+	/// Iterate over the children of a directory sorted by name.
+	///
+	/// # Panics
+	///
+	/// If `dir` is not a directory in this tree.
+	pub fn children(
+		&self,
+		dir: u32,
+	) -> impl DoubleEndedIterator<Item = (&[u8], Node)> + ExactSizeIterator + '_ {
+		self.children_from(dir, 0)
+	}
+
+	/// Iterate over the children of a directory sorted by name, skipping the first `start`.
+	///
+	/// Unlike skipping on [`Tree::children`] this takes constant time.
+	///
+	/// # Panics
+	///
+	/// If `dir` is not a directory in this tree.
+	pub fn children_from(
+		&self,
+		dir: u32,
+		start: usize,
+	) -> impl DoubleEndedIterator<Item = (&[u8], Node)> + ExactSizeIterator + '_ {
+		let d = &self.dirs[dir as usize];
+		d[start.min(d.len())..]
+			.iter()
+			.map(|&(s, l, v)| (self.name(s, l), v))
+	}
+
+	/// Find a direct child of a directory.
+	///
+	/// # Panics
+	///
+	/// If `dir` is not a directory in this tree.
+	pub fn child(&self, dir: u32, name: &[u8]) -> Option<Node> {
+		let d = &self.dirs[dir as usize];
+		d.binary_search_by(|&(s, l, _)| self.name(s, l).cmp(name))
+			.ok()
+			.map(|i| d[i].2)
+	}
+
+	fn name(&self, start: u32, len: u8) -> &[u8] {
+		let start = start as usize;
+		&self.names[start..start + usize::from(len)]
+	}
+
+	/// Find the node at `path`. An empty path is the root.
+	pub fn lookup(&self, path: &[u8]) -> Option<Node> {
+		components(path).try_fold(Node::Dir(0), |n, p| match n {
+			Node::Dir(d) => self.child(d, p),
+			Node::File(_) => None,
+		})
+	}
+
+	/// Whether a file or directory exists at `path`. The root always exists.
+	pub fn contains(&self, path: &[u8]) -> bool {
+		self.lookup(path).is_some()
+	}
+
+	/// Iterate over the names of the subdirectories of the directory at `path` in sorted order.
+	///
+	/// Yields nothing if `path` is not a directory.
+	pub fn subdirs(&self, path: &[u8]) -> impl Iterator<Item = &[u8]> + '_ {
+		let dir = match self.lookup(path) {
+			Some(Node::Dir(d)) => Some(d),
+			_ => None,
+		};
+		dir.into_iter().flat_map(move |d| {
+			self.children(d)
+				.filter_map(|(k, v)| matches!(v, Node::Dir(_)).then_some(k))
+		})
+	}
+}
+
+impl<R: fmt::Display> fmt::Display for TreeError<R> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::TooDeep(i) => write!(f, "path of entry {} is too deep", i),
+			Self::TooLarge => f.write_str("names in tree exceed 4 GiB"),
+			Self::Other(e) => e.fmt(f),
+		}
+	}
+}
+
+impl<R: core::error::Error + 'static> core::error::Error for TreeError<R> {
+	fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+		match self {
+			Self::Other(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+/// Split a path into its non-empty components.
+pub fn components(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+	path.split(|&c| c == b'/').filter(|p| !p.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn split(path: &str) -> Vec<&[u8]> {
+		components(path.as_bytes()).collect()
+	}
+
+	#[test]
+	fn components_edge_cases() {
+		assert!(split("").is_empty());
+		assert!(split("/").is_empty());
+		assert!(split("///").is_empty());
+		assert_eq!(split("a"), [b"a"]);
+		assert_eq!(split("/a//b/"), [&b"a"[..], b"b"]);
+		assert_eq!(split("a/./.."), [&b"a"[..], b".", b".."]);
+		assert_eq!(split(" / "), [b" ", b" "]);
+	}
+
+	fn tree(files: &[(&str, &[u8])]) -> Tree {
+		let img = crate::tests::image(files);
+		Tree::new(&crate::tests::load(&img), 16, crate::tests::slice_io(&img)).unwrap()
+	}
+
+	#[test]
+	fn contains() {
+		let t = tree(&[("a/b/c", b"1"), ("d", b"2")]);
+		for p in [
+			"", "/", "//", "a", "a/", "/a/b//", "a/b/c", "a/b/c/", "d", "/d/",
+		] {
+			assert!(t.contains(p.as_bytes()), "{:?}", p);
+		}
+		for p in ["b", "a/c", "a/b/c/d", "d/e", "a/b/cc", "aa"] {
+			assert!(!t.contains(p.as_bytes()), "{:?}", p);
+		}
+		assert_eq!(t.lookup(b"/"), Some(Node::Dir(0)));
+		assert_eq!(t.lookup(b"d/"), Some(Node::File(1)));
+	}
+
+	#[test]
+	fn subdirs() {
+		let t = tree(&[("a/x/1", b""), ("a/b", b""), ("a/c/2", b""), ("d", b"")]);
+		let s = |p: &str| t.subdirs(p.as_bytes()).collect::<Vec<_>>();
+		assert_eq!(s("a"), [b"c", b"x"]);
+		assert_eq!(s(""), [b"a"]);
+		assert!(s("a/x").is_empty());
+		assert!(s("d").is_empty());
+		assert!(s("missing").is_empty());
+	}
+
+	#[test]
+	fn too_deep() {
+		let img = crate::tests::image(&[("a/b", b""), ("a/b/c", b""), ("d", b"")]);
+		let h = crate::tests::load(&img);
+		let r = Tree::new(&h, 2, crate::tests::slice_io(&img));
+		assert!(matches!(r, Err(TreeError::TooDeep(1))));
+		assert!(Tree::new(&h, 3, crate::tests::slice_io(&img)).is_ok());
+	}
+}
@@ -0,0 +1,668 @@
+use crate::{
+	hash_name, BlockSize, Entry, Header, Op, ParseHeaderError, FLAG_METADATA, FLAG_PATH_INDEX,
+	MAGIC, VERSION,
+};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{convert::Infallible, fmt};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WriteError {
+	NameTooLong,
+	MetadataTooLong,
+	FileTooLarge,
+	ImageTooLarge,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StreamError<R> {
+	Layout(WriteError),
+	Other(R),
+}
+
+/// An error from [`Writer::from_existing`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum FromExistingError<R> {
+	Header(ParseHeaderError<R>),
+	/// Reading the metadata, names or data failed.
+	Other(R),
+}
+
+impl fmt::Display for WriteError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::NameTooLong => "name is longer than 255 bytes",
+			Self::MetadataTooLong => "metadata key or value is longer than 255 bytes",
+			Self::FileTooLarge => "file is larger than 4 GiB",
+			Self::ImageTooLarge => "image is too large to address",
+		})
+	}
+}
+
+impl core::error::Error for WriteError {}
+
+impl<R: fmt::Display> fmt::Display for StreamError<R> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Layout(e) => e.fmt(f),
+			Self::Other(e) => e.fmt(f),
+		}
+	}
+}
+
+impl<R: core::error::Error + 'static> core::error::Error for StreamError<R> {
+	fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+		match self {
+			Self::Layout(e) => Some(e),
+			Self::Other(e) => Some(e),
+		}
+	}
+}
+
+impl<R: fmt::Display> fmt::Display for FromExistingError<R> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Header(e) => e.fmt(f),
+			Self::Other(e) => e.fmt(f),
+		}
+	}
+}
+
+impl<R: core::error::Error + 'static> core::error::Error for FromExistingError<R> {
+	fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+		match self {
+			Self::Header(e) => Some(e),
+			Self::Other(e) => Some(e),
+		}
+	}
+}
+
+/// Builds an image in memory.
+///
+/// Files are written sorted by name.
+/// The metadata section records the tool name and version unless overridden.
+#[derive(Debug)]
+pub struct Writer {
+	stream: StreamWriter,
+	data: BTreeMap<Box<[u8]>, Box<[u8]>>,
+}
+
+/// Builds an image without keeping file data in memory.
+///
+/// Only the name and size of each file are declared up front. Once everything up to the file
+/// data has been written with [`StreamWriter::start`], the data of each file is streamed in
+/// name order.
+///
+/// The output is identical to that of [`Writer`] for the same files.
+#[derive(Debug)]
+pub struct StreamWriter {
+	block_size: BlockSize,
+	files: BTreeMap<Box<[u8]>, u32>,
+	metadata: BTreeMap<Box<[u8]>, Box<[u8]>>,
+	path_index: bool,
+}
+
+/// Writes the data of each file of a [`StreamWriter`].
+pub struct DataWriter<R, W>
+where
+	W: FnMut(&[u8]) -> Result<(), R>,
+{
+	out: W,
+	files: Vec<(Box<[u8]>, u64, u32)>,
+	next: usize,
+	position: u64,
+	buf: Box<[u8]>,
+}
+
+/// Configures a [`Writer`].
+///
+/// By default blocks are 4 KiB and there is no path index.
+#[derive(Clone, Debug)]
+pub struct WriterBuilder {
+	block_size: BlockSize,
+	path_index: bool,
+}
+
+impl WriterBuilder {
+	pub fn block_size(mut self, block_size: BlockSize) -> Self {
+		self.block_size = block_size;
+		self
+	}
+
+	/// Whether to add an index for finding entries by name with [`Header::resolve_path`].
+	pub fn path_index(mut self, enable: bool) -> Self {
+		self.path_index = enable;
+		self
+	}
+
+	pub fn build(self) -> Writer {
+		let mut w = Writer::new(self.block_size);
+		w.set_path_index(self.path_index);
+		w
+	}
+}
+
+impl Default for WriterBuilder {
+	fn default() -> Self {
+		Self { block_size: BlockSize::new(12).unwrap(), path_index: false }
+	}
+}
+
+impl Writer {
+	pub fn new(block_size: BlockSize) -> Self {
+		Self { stream: StreamWriter::new(block_size), data: Default::default() }
+	}
+
+	pub fn builder() -> WriterBuilder {
+		WriterBuilder::default()
+	}
+
+	/// Read all files from an existing image so more can be added to it.
+	///
+	/// Like everything added to a [`Writer`], the data of every file is copied into memory, so
+	/// this needs as much memory as the files in the image take up. To rebuild a large image,
+	/// stream the files of the old image into a [`StreamWriter`] instead.
+	pub fn from_existing<R, Io>(mut io: Io) -> Result<Self, FromExistingError<R>>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		io(Op::Seek(0)).map_err(FromExistingError::Other)?;
+		let header = Header::load(|b| io(Op::Read(b))).map_err(FromExistingError::Header)?;
+		let mut s = Self::new(header.block_size());
+		let (mut k, mut v) = ([0; 255], [0; 255]);
+		let mut m = header.metadata(&mut io).map_err(FromExistingError::Other)?;
+		while let Some(r) = m.next(&mut k, &mut v) {
+			let (k, v) = r.map_err(FromExistingError::Other)?;
+			s.stream.metadata.insert(k.into(), v.into());
+		}
+		let mut buf = [0; 255];
+		for i in 0..header.file_count() {
+			s.read_file(&header, i, &mut buf, &mut io)
+				.map_err(FromExistingError::Other)?;
+		}
+		Ok(s)
+	}
+
+	/// Add a file, replacing any existing file with the same name.
+	pub fn add(&mut self, name: &[u8], data: &[u8]) -> Result<(), WriteError> {
+		let size = u32::try_from(data.len()).map_err(|_| WriteError::FileTooLarge)?;
+		self.stream.add(name, size)?;
+		self.data.insert(name.into(), data.into());
+		Ok(())
+	}
+
+	/// Set a metadata key, replacing any existing value.
+	///
+	/// A build timestamp is not recorded automatically and can be added with this.
+	pub fn set_metadata(&mut self, key: &[u8], value: &[u8]) -> Result<(), WriteError> {
+		self.stream.set_metadata(key, value)
+	}
+
+	/// Remove a metadata key.
+	pub fn remove_metadata(&mut self, key: &[u8]) {
+		self.stream.remove_metadata(key)
+	}
+
+	/// Whether to add an index for finding entries by name with [`Header::resolve_path`].
+	pub fn set_path_index(&mut self, enable: bool) {
+		self.stream.set_path_index(enable)
+	}
+
+	/// Lay out and serialize the image.
+	pub fn finish(self) -> Result<Vec<u8>, WriteError> {
+		let mut img = Vec::new();
+		let mut w = self
+			.stream
+			.start(|b| {
+				img.extend_from_slice(b);
+				Ok::<_, Infallible>(())
+			})
+			.map_err(|e| match e {
+				StreamError::Layout(e) => e,
+				StreamError::Other(e) => match e {},
+			})?;
+		for data in self.data.values() {
+			let mut data = &data[..];
+			let r = w.write_file(|b| {
+				let (l, r) = data.split_at(b.len());
+				b.copy_from_slice(l);
+				data = r;
+				Ok(())
+			});
+			r.unwrap_or_else(|e| match e {});
+		}
+		let _ = w.finish();
+		Ok(img)
+	}
+
+	fn read_file<R, Io>(
+		&mut self,
+		header: &Header,
+		index: u32,
+		buf: &mut [u8; 255],
+		mut io: Io,
+	) -> Result<(), R>
+	where
+		Io: FnMut(Op<'_>) -> Result<(), R>,
+	{
+		let e = header.get(index, &mut io).unwrap()?;
+		let name = e.name(buf, &mut io)?;
+		let mut data = alloc::vec![0; e.size() as usize].into_boxed_slice();
+		io(Op::Seek(e.offset(header)))?;
+		io(Op::Read(&mut data))?;
+		self.stream.files.insert(name.into(), e.size());
+		self.data.insert(name.into(), data);
+		Ok(())
+	}
+}
+
+impl Default for Writer {
+	fn default() -> Self {
+		Self::builder().build()
+	}
+}
+
+impl StreamWriter {
+	pub fn new(block_size: BlockSize) -> Self {
+		let mut s = Self {
+			block_size,
+			files: Default::default(),
+			metadata: Default::default(),
+			path_index: false,
+		};
+		s.metadata.insert((*b"tool").into(), (*b"nrofs").into());
+		s.metadata.insert(
+			(*b"version").into(),
+			env!("CARGO_PKG_VERSION").as_bytes().into(),
+		);
+		s
+	}
+
+	/// Declare a file, replacing any existing file with the same name.
+	pub fn add(&mut self, name: &[u8], size: u32) -> Result<(), WriteError> {
+		if name.len() > 255 {
+			return Err(WriteError::NameTooLong);
+		}
+		self.files.insert(name.into(), size);
+		Ok(())
+	}
+
+	/// Set a metadata key, replacing any existing value.
+	pub fn set_metadata(&mut self, key: &[u8], value: &[u8]) -> Result<(), WriteError> {
+		if key.len() > 255 || value.len() > 255 {
+			return Err(WriteError::MetadataTooLong);
+		}
+		self.metadata.insert(key.into(), value.into());
+		Ok(())
+	}
+
+	/// Remove a metadata key.
+	pub fn remove_metadata(&mut self, key: &[u8]) {
+		self.metadata.remove(key);
+	}
+
+	/// Whether to add an index for finding entries by name with [`Header::resolve_path`].
+	pub fn set_path_index(&mut self, enable: bool) {
+		self.path_index = enable;
+	}
+
+	/// Lay out the image and write everything up to the file data.
+	///
+	/// The header and entry table are followed by the metadata section and path index, if any,
+	/// and the filenames.
+	/// File data starts at the first block after the filenames.
+	pub fn start<R, W>(self, mut out: W) -> Result<DataWriter<R, W>, StreamError<R>>
+	where
+		W: FnMut(&[u8]) -> Result<(), R>,
+	{
+		let align = |n: u64| n + self.block_size.padding_for(n);
+		let too_large = |_| StreamError::Layout(WriteError::ImageTooLarge);
+
+		let count = u32::try_from(self.files.len()).map_err(too_large)?;
+		let meta_len = self
+			.metadata
+			.iter()
+			.map(|(k, v)| 2 + k.len() + v.len())
+			.sum::<usize>();
+		let (mut flags, meta_len) = match meta_len {
+			0 => (0, 0),
+			n => (FLAG_METADATA, 4 + n as u64),
+		};
+		let index_len = if self.path_index {
+			flags |= FLAG_PATH_INDEX;
+			4 + 8 * u64::from(count)
+		} else {
+			0
+		};
+		let table_end = 16 + 12 * u64::from(count);
+		let names_len = self.files.keys().map(|k| 1 + k.len() as u64).sum::<u64>();
+
+		let mut table = Vec::with_capacity(self.files.len() * 12);
+		let mut files = Vec::with_capacity(self.files.len());
+		let mut name_addr = table_end + meta_len + index_len;
+		let mut data_addr = align(name_addr + names_len);
+		for (name, size) in self.files {
+			let filename_addr = u32::try_from(name_addr).map_err(too_large)?;
+			let block_addr =
+				u32::try_from(data_addr >> self.block_size.as_shift()).map_err(too_large)?;
+			let e = Entry { filename_addr, block_addr, file_size: size };
+			table.extend_from_slice(&e.to_le_bytes());
+			name_addr += 1 + name.len() as u64;
+			files.push((name, data_addr, size));
+			data_addr = align(data_addr + u64::from(size));
+		}
+
+		let mut header = [0; 16];
+		header[..8].copy_from_slice(&MAGIC);
+		header[8..12].copy_from_slice(&[VERSION, self.block_size.as_shift(), flags, 0]);
+		header[12..].copy_from_slice(&count.to_le_bytes());
+		let mut w = |b: &[u8]| out(b).map_err(StreamError::Other);
+		w(&header)?;
+		w(&table)?;
+
+		if flags & FLAG_METADATA != 0 {
+			w(&(meta_len as u32 - 4).to_le_bytes())?;
+			for (k, v) in self.metadata.iter() {
+				w(&[k.len() as u8])?;
+				w(k)?;
+				w(&[v.len() as u8])?;
+				w(v)?;
+			}
+		}
+
+		if flags & FLAG_PATH_INDEX != 0 {
+			let mut index = (0..count)
+				.zip(files.iter())
+				.map(|(i, (name, ..))| (hash_name(name), i))
+				.collect::<Vec<_>>();
+			index.sort_unstable();
+			w(&count.to_le_bytes())?;
+			for (h, i) in index {
+				w(&h.to_le_bytes())?;
+				w(&i.to_le_bytes())?;
+			}
+		}
+
+		for (name, ..) in files.iter() {
+			w(&[name.len() as u8])?;
+			w(name)?;
+		}
+
+		let buf_len = self.block_size.as_bytes().min(1 << 16) as usize;
+		Ok(DataWriter {
+			out,
+			files,
+			next: 0,
+			position: name_addr,
+			buf: alloc::vec![0; buf_len].into(),
+		})
+	}
+}
+
+impl<R, W> DataWriter<R, W>
+where
+	W: FnMut(&[u8]) -> Result<(), R>,
+{
+	/// The name of the file whose data is to be written next.
+	pub fn next_name(&self) -> Option<&[u8]> {
+		self.files.get(self.next).map(|(name, ..)| &**name)
+	}
+
+	/// Write the data of the next file, reading it from `src` in chunks.
+	///
+	/// # Panics
+	///
+	/// If the data of all files has already been written.
+	pub fn write_file<S>(&mut self, mut src: S) -> Result<(), R>
+	where
+		S: FnMut(&mut [u8]) -> Result<(), R>,
+	{
+		let (_, offset, size) = self.files[self.next];
+		self.buf.fill(0);
+		while self.position < offset {
+			let n = (offset - self.position).min(self.buf.len() as u64);
+			(self.out)(&self.buf[..n as usize])?;
+			self.position += n;
+		}
+		let mut left = u64::from(size);
+		while left > 0 {
+			let n = left.min(self.buf.len() as u64) as usize;
+			let b = &mut self.buf[..n];
+			src(b)?;
+			(self.out)(b)?;
+			left -= b.len() as u64;
+		}
+		self.position += u64::from(size);
+		self.next += 1;
+		Ok(())
+	}
+
+	/// Return the output after the data of all files has been written.
+	///
+	/// # Panics
+	///
+	/// If the data of any file has not been written yet.
+	pub fn finish(self) -> W {
+		assert!(
+			self.next == self.files.len(),
+			"not all files have been written"
+		);
+		self.out
+	}
+}
+
+/// Pick a block size for files of the given sizes, trading padding against the amount of blocks.
+///
+/// Padding grows with the block size while the amount of blocks each file spans shrinks, so the
+/// cost of a block size is the padding after every file and after the names, which is assumed to
+/// be a whole block, plus [`BLOCK_COST`] bytes for each block a file spans. Many small files get a
+/// block size close to their size and a few large files get a large one.
+/// Returns `None` if the data is too large for any block size.
+pub fn recommend_block_size(sizes: &[u64]) -> Option<BlockSize> {
+	(0..32)
+		.map(|s| BlockSize::new(s).unwrap())
+		.filter_map(|bs| {
+			let (blocks, padding) = sizes.iter().fold((0u64, 0u64), |(n, p), &s| {
+				let b = (s >> bs.as_shift()) + u64::from(s & bs.mask() != 0);
+				(n.saturating_add(b), p.saturating_add(bs.padding_for(s)))
+			});
+			let cost = u128::from(padding)
+				+ u128::from(bs.as_bytes())
+				+ u128::from(blocks) * u128::from(BLOCK_COST);
+			(blocks <= 1 << 32).then_some((cost, bs))
+		})
+		.min()
+		.map(|(_, bs)| bs)
+}
+
+/// The cost in bytes [`recommend_block_size`] assigns to each block of file data, for the
+/// overhead of addressing and reading it separately.
+pub const BLOCK_COST: u64 = 512;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::{image, slice_io};
+
+	/// The name and data of every file in an image.
+	fn files(img: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut io = slice_io(img);
+		io(Op::Seek(0)).unwrap();
+		let h = Header::load(|b| io(Op::Read(b))).unwrap();
+		let mut buf = [0; 255];
+		(0..h.file_count())
+			.map(|i| {
+				let e = h.get(i, &mut io).unwrap().unwrap();
+				let name = e.name(&mut buf, &mut io).unwrap().to_vec();
+				let mut data = alloc::vec![0; e.size() as usize];
+				io(Op::Seek(e.offset(&h))).unwrap();
+				io(Op::Read(&mut data)).unwrap();
+				(name, data)
+			})
+			.collect()
+	}
+
+	const FILES: &[(&str, &[u8])] = &[
+		("dir/a", b"first"),
+		("dir/b", b""),
+		("z", &[0xa5; 3000]),
+		("c", b"c"),
+	];
+
+	/// Build an image of [`FILES`] with a [`StreamWriter`].
+	fn streamed(block_size: BlockSize, path_index: bool) -> Vec<u8> {
+		let mut s = StreamWriter::new(block_size);
+		s.set_path_index(path_index);
+		s.set_metadata(b"comment", b"streamed").unwrap();
+		for (name, data) in FILES {
+			s.add(name.as_bytes(), data.len() as u32).unwrap();
+		}
+		let mut img = Vec::new();
+		let mut w = s
+			.start(|b| {
+				img.extend_from_slice(b);
+				Ok::<_, ()>(())
+			})
+			.unwrap();
+		while let Some(name) = w.next_name() {
+			let name = core::str::from_utf8(name).unwrap();
+			let mut data = FILES.iter().find(|(n, _)| *n == name).unwrap().1;
+			w.write_file(|b| {
+				let (l, r) = data.split_at(b.len());
+				b.copy_from_slice(l);
+				data = r;
+				Ok(())
+			})
+			.unwrap();
+		}
+		let _ = w.finish();
+		img
+	}
+
+	#[test]
+	fn stream_writer_matches_writer() {
+		for (shift, path_index) in [(0, false), (4, true), (12, false), (16, true)] {
+			let bs = BlockSize::new(shift).unwrap();
+			let mut w = Writer::new(bs);
+			w.set_path_index(path_index);
+			w.set_metadata(b"comment", b"streamed").unwrap();
+			for (name, data) in FILES {
+				w.add(name.as_bytes(), data).unwrap();
+			}
+			assert_eq!(streamed(bs, path_index), w.finish().unwrap());
+		}
+	}
+
+	#[test]
+	#[should_panic]
+	fn stream_writer_finish_early() {
+		let mut s = StreamWriter::new(BlockSize::new(9).unwrap());
+		s.add(b"a", 1).unwrap();
+		let _ = s.start(|_| Ok::<_, ()>(())).unwrap().finish();
+	}
+
+	#[test]
+	fn reproducible() {
+		let build = |order: &mut dyn Iterator<Item = &(&str, &[u8])>| {
+			let mut w = Writer::default();
+			for (name, data) in order {
+				w.add(name.as_bytes(), data).unwrap();
+			}
+			w.finish().unwrap()
+		};
+		let a = build(&mut FILES.iter());
+		assert_eq!(a, build(&mut FILES.iter()));
+		assert_eq!(a, build(&mut FILES.iter().rev()));
+	}
+
+	#[test]
+	fn recommend_small_files() {
+		let bs = recommend_block_size(&[10; 1000]).unwrap();
+		assert_eq!(bs.as_shift(), 4);
+		let bs = recommend_block_size(&[100, 120, 90, 128]).unwrap();
+		assert_eq!(bs.as_shift(), 7);
+	}
+
+	#[test]
+	fn recommend_large_files() {
+		let bs = recommend_block_size(&[1 << 30; 3]).unwrap();
+		assert!(bs.as_shift() >= 16, "{:?}", bs);
+		assert!(bs > recommend_block_size(&[4096; 3]).unwrap());
+	}
+
+	#[test]
+	fn recommend_unaddressable() {
+		assert_eq!(recommend_block_size(&[]), BlockSize::new(0));
+		assert!(recommend_block_size(&[u64::MAX; 2]).is_none());
+		assert!(recommend_block_size(&[u32::MAX.into(); 1 << 12]).is_some());
+	}
+
+	#[test]
+	fn from_existing_round_trip() {
+		let img = image(&[("a/b", b"hello"), ("c", b""), ("d", &[7; 5000])]);
+		let w = Writer::from_existing(slice_io(&img)).unwrap();
+		assert_eq!(w.finish().unwrap(), img);
+	}
+
+	#[test]
+	fn from_existing_add() {
+		let img = image(&[("b", b"old"), ("c", b"kept")]);
+		let mut w = Writer::from_existing(slice_io(&img)).unwrap();
+		w.add(b"a", b"new").unwrap();
+		w.add(b"b", b"replaced").unwrap();
+		let f = files(&w.finish().unwrap());
+		let f = f.iter().map(|(n, d)| (&n[..], &d[..])).collect::<Vec<_>>();
+		assert_eq!(
+			f,
+			[
+				(&b"a"[..], &b"new"[..]),
+				(b"b", b"replaced"),
+				(b"c", b"kept")
+			]
+		);
+	}
+
+	#[test]
+	fn builder() {
+		let load = |w: Writer| {
+			let img = w.finish().unwrap();
+			(crate::tests::load(&img), img)
+		};
+		let (h, _) = load(Writer::default());
+		assert_eq!((h.block_size().as_shift(), h.has_path_index()), (12, false));
+		let w = Writer::builder()
+			.block_size(BlockSize::new(9).unwrap())
+			.path_index(true);
+		let mut w = w.build();
+		for (name, data) in FILES {
+			w.add(name.as_bytes(), data).unwrap();
+		}
+		let (h, img) = load(w);
+		assert_eq!((h.block_size().as_shift(), h.has_path_index()), (9, true));
+		assert_eq!(h.resolve_path(b"dir/b", slice_io(&img)), Ok(Some(2)));
+		assert_eq!(files(&img), files(&image(FILES)));
+	}
+
+	#[test]
+	fn from_existing_keeps_metadata() {
+		let mut w = Writer::default();
+		w.set_metadata(b"comment", b"hi").unwrap();
+		let img = w.finish().unwrap();
+		let w = Writer::from_existing(slice_io(&img)).unwrap();
+		assert_eq!(&*w.stream.metadata[&b"comment"[..]], b"hi");
+	}
+
+	#[test]
+	fn from_existing_errors() {
+		let img = image(&[("a", &[1; 100])]);
+		let r = Writer::from_existing(slice_io(&img[..img.len() - 1]));
+		assert!(matches!(r, Err(FromExistingError::Other(()))));
+		let r = Writer::from_existing(slice_io(&img[..8]));
+		assert!(matches!(
+			r,
+			Err(FromExistingError::Header(ParseHeaderError::Other(())))
+		));
+	}
+}